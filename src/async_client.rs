@@ -0,0 +1,157 @@
+use std::fmt::Debug;
+
+use futures_util::{
+    stream::{self, SplitSink, SplitStream},
+    SinkExt, Stream, StreamExt,
+};
+use nostr::{
+    event::{Event, EventBuilder, EventId, Kind},
+    key::{Keys, PublicKey},
+    message::{ClientMessage, RelayMessage, SubscriptionId},
+    types::Filter,
+    util::JsonUtil,
+};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+use crate::{normalize_relay_url, parse_relay_message, try_decrypt_dm, Error};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Async counterpart of `WsClient` for a single relay, on tokio. There is no
+/// listener thread: the socket is driven by the task awaiting the client, so
+/// keep receiving for pings to be answered.
+pub struct WsClientAsync {
+    relay: String,
+    keys: Option<Keys>,
+    sink: SplitSink<Socket, Message>,
+    stream: SplitStream<Socket>,
+}
+
+impl Debug for WsClientAsync {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WsClientAsync")
+            .field("relay", &self.relay)
+            .field("keys", &self.keys)
+            .finish()
+    }
+}
+
+impl WsClientAsync {
+    /// Connect to `relay`, without keys the client is read-only.
+    pub async fn connect<T: Into<String>>(relay: T, keys: Option<Keys>) -> Result<Self, Error> {
+        let relay = normalize_relay_url(&relay.into())?;
+        let (socket, _) = tokio_tungstenite::connect_async(relay.as_str()).await?;
+        let (sink, stream) = socket.split();
+        Ok(WsClientAsync {
+            relay,
+            keys,
+            sink,
+            stream,
+        })
+    }
+
+    pub fn get_relay(&self) -> &str {
+        &self.relay
+    }
+
+    pub fn get_keys(&self) -> Result<&Keys, Error> {
+        self.keys.as_ref().ok_or(Error::KeysMissing)
+    }
+
+    pub fn pubkey(&self) -> Result<PublicKey, Error> {
+        Ok(self.get_keys()?.public_key())
+    }
+
+    pub async fn send_client_message(&mut self, msg: ClientMessage) -> Result<(), Error> {
+        self.sink.send(Message::Text(msg.as_json())).await?;
+        Ok(())
+    }
+
+    pub async fn subscribe(&mut self, filters: Vec<Filter>) -> Result<SubscriptionId, Error> {
+        let id = SubscriptionId::generate();
+        self.send_client_message(ClientMessage::req(id.clone(), filters))
+            .await?;
+        Ok(id)
+    }
+
+    pub async fn close_subscription(&mut self, id: SubscriptionId) -> Result<(), Error> {
+        self.send_client_message(ClientMessage::close(id)).await
+    }
+
+    pub async fn subscribe_dm(&mut self) -> Result<SubscriptionId, Error> {
+        let filter = Filter::new()
+            .kind(Kind::EncryptedDirectMessage)
+            .pubkey(self.pubkey()?);
+        self.subscribe(vec![filter]).await
+    }
+
+    pub async fn post_event(&mut self, event: EventBuilder) -> Result<EventId, Error> {
+        let event = event
+            .to_event(self.get_keys()?)
+            .map_err(|_| Error::SignEvent)?;
+        let id = event.id;
+        self.send_client_message(ClientMessage::event(event))
+            .await?;
+        Ok(id)
+    }
+
+    /// Wait for the next relay message, returning the event it carries if
+    /// any. Events with an invalid id or signature return
+    /// `Error::InvalidEvent`, DMs are decrypted.
+    pub async fn try_receive(&mut self) -> Result<Option<Event>, Error> {
+        let msg = match self.stream.next().await {
+            Some(msg) => msg?,
+            None => return Err(Error::ConnectionClosed),
+        };
+        let text = match msg {
+            Message::Text(text) => text,
+            Message::Close(_) => return Err(Error::ConnectionClosed),
+            // pings are answered by tungstenite on the next send or receive
+            _ => return Ok(None),
+        };
+        match parse_relay_message(&text)? {
+            RelayMessage::Event { event, .. } => {
+                let mut event = *event;
+                // before decrypting anything a relay could have forged
+                if event.verify().is_err() {
+                    return Err(Error::InvalidEvent(event.id));
+                }
+                #[allow(deprecated)]
+                if event.kind == Kind::EncryptedDirectMessage && self.keys.is_some() {
+                    event.content = try_decrypt_dm(self.get_keys()?, &event)?;
+                }
+                Ok(Some(event))
+            }
+            RelayMessage::Closed {
+                subscription_id,
+                message,
+            } => Err(Error::SubscriptionClosed {
+                id: subscription_id,
+                message,
+            }),
+            _ => Ok(None),
+        }
+    }
+
+    /// Stream of the incoming events, ending when the relay closes the
+    /// connection.
+    pub fn events(&mut self) -> impl Stream<Item = Result<Event, Error>> + '_ {
+        stream::unfold(self, |client| async move {
+            loop {
+                match client.try_receive().await {
+                    Ok(Some(event)) => return Some((Ok(event), client)),
+                    Ok(None) => {}
+                    Err(Error::ConnectionClosed) => return None,
+                    Err(e) => return Some((Err(e), client)),
+                }
+            }
+        })
+    }
+
+    /// Send a Close frame to the relay.
+    pub async fn stop(&mut self) -> Result<(), Error> {
+        self.sink.send(Message::Close(None)).await?;
+        Ok(())
+    }
+}
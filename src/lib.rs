@@ -1,34 +1,94 @@
 use std::{
+    collections::{HashMap, HashSet, VecDeque},
     fmt::Debug,
-    io::ErrorKind,
-    sync::mpsc::{self, Receiver, Sender},
-    time::{Duration, SystemTime},
+    io::{BufRead, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender, SyncSender},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime},
 };
 
+use native_tls::{TlsConnector, TlsStream};
 use nostr::{
-    event::{Event, EventBuilder, Kind, Tag},
-    key::{Keys, PublicKey},
+    event::{Event, EventBuilder, EventId, Kind, Tag, TagKind, UnsignedEvent},
+    key::{Keys, PublicKey, SecretKey},
     message::{ClientMessage, RawRelayMessage, RelayMessage, SubscriptionId},
-    nips::nip04,
-    types::{Filter, Timestamp},
+    nips::{
+        nip01::Coordinate,
+        nip04,
+        nip11::RelayInformationDocument,
+        nip13,
+        nip19::{FromBech32, Nip19Event, ToBech32},
+        nip44,
+        nip59::UnwrappedGift,
+    },
+    secp256k1::rand::{rngs::OsRng, RngCore},
+    types::{Alphabet, Filter, Metadata, SingleLetterTag, Timestamp},
     util::JsonUtil,
 };
-use websocket::{
-    stream::sync::NetworkStream, sync::Client, url::ParseError, ClientBuilder, OwnedMessage,
-    WebSocketError,
+use socket2::{SockRef, TcpKeepalive};
+use socks::Socks5Stream;
+use tungstenite::{
+    client::IntoClientRequest,
+    handshake::HandshakeError,
+    http::{HeaderName, HeaderValue},
+    Message as WsMessage, WebSocket,
 };
+use url::{Host, ParseError, Url};
 
+#[cfg(feature = "async")]
+mod async_client;
+mod reconnect;
+#[cfg(feature = "store")]
+mod store;
+
+#[cfg(feature = "async")]
+pub use async_client::WsClientAsync;
 pub use nostr;
-pub use websocket;
+pub use reconnect::{ConstantBackoff, ExponentialBackoff, LinearBackoff, ReconnectStrategy};
+#[cfg(feature = "store")]
+pub use store::{EventStore, MemoryStore};
+pub use tungstenite;
 
-const PING_INTERVAL: u64 = 5; // ping interval in seconds
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+const PONG_TIMEOUT: Duration = Duration::from_secs(15); // disconnect if no pong received since
+const POLL_INTERVAL: Duration = Duration::from_millis(10); // blocking helpers poll interval
+const CLOCK_SKEW_SAMPLES: usize = 32;
+const CLOCK_SKEW_THRESHOLD: u64 = 30; // skew compensated above, in seconds
+const LARGE_CONTENT: usize = 64 * 1024; // warn when posting content bigger than this, in bytes
+const MAX_IN_FLIGHT: usize = 1024; // published events waiting for an OK that we keep track of
+const READ_TIMEOUT: Duration = Duration::from_millis(10); // used if the socket can't be non-blocking
+const PROCESSING_SAMPLES: usize = 64; // events the processing time average is computed on
+const PAUSE_BUFFER: usize = 10_000; // messages held by the listener while paused
+const CLOSE_TIMEOUT: Duration = Duration::from_millis(500); // wait for the relay Close echo
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2); // wait for the listeners to exit
+const MAX_NOTICES: usize = 100; // notices kept for `WsClient::try_notice()`
+const RECONNECT_MIN: Duration = Duration::from_secs(1); // default reconnection backoff
+const RECONNECT_MAX: Duration = Duration::from_secs(60);
+const MAX_FINISHED: usize = 64; // query() subscriptions whose late messages are dropped
+const SWITCH_TIMEOUT: Duration = Duration::from_secs(10); // new relay EOSE on switch_relay()
+const RELAY_INFO_TIMEOUT: Duration = Duration::from_secs(10); // NIP-11 document fetch
+const MAX_RELAY_INFO: u64 = 64 * 1024; // NIP-11 response size cap, in bytes
+const CHANNEL_CAPACITY: usize = 10_000; // relay messages queued for the client by default
+const IDLE_MIN: Duration = Duration::from_micros(50); // listener sleep, doubled while idle
+const IDLE_MAX: Duration = Duration::from_millis(10);
 
 #[derive(Debug)]
 pub enum Error {
-    WebSocket(WebSocketError),
     Parse(ParseError),
+    DnsResolution {
+        host: String,
+    },
     Listen,
     Send,
+    /// The send queue of every relay is full, see
+    /// `WsClientBuilder::channel_capacity()`.
+    SendQueueFull,
     Receive,
     NonBlocking,
     NotConnected,
@@ -37,15 +97,140 @@ pub enum Error {
     Nip04Encrypt,
     Nip04Decrypt,
     NotNip04,
+    Nip44Encrypt,
+    Nip44Decrypt,
+    NotNip44,
+    GiftWrap,
+    Unwrap,
+    /// The seal of a gift wrap isn't signed by the author of its rumor.
+    SenderMismatch,
     SignEvent,
+    NoAuthChallenge,
     ConnectionClosed,
+    /// Auto-reconnect gave up on every relay.
+    ReconnectFailed,
     RawRelayMessage,
     RelayMessage,
+    FilterNarrowed {
+        detail: String,
+    },
+    InvalidTimeRange,
+    InvalidPongTimeout,
+    /// Not a `ws://` or `wss://` url.
+    InvalidRelayUrl(String),
+    /// An addressable event (kind 30000-39999) has no `d` tag, add one with
+    /// `Tag::identifier()` or relays will treat it as the empty identifier.
+    MissingIdentifier(Kind),
+    KeyGeneration,
+    /// Not a bech32 string of the expected kind (`nsec`, `note`, ...).
+    InvalidBech32,
+    Cancelled,
+    SubscriptionNotFound(SubscriptionId),
+    RelayNotFound(String),
+    PublishTimeout,
+    EoseTimeout,
+    CountTimeout,
+    PowTimeout,
+    /// The relay answered OK false for an event, with its reason.
+    Rejected(String),
+    InvalidCoordinate(String),
+    /// A received event has an invalid id or signature.
+    InvalidEvent(EventId),
+    /// The relay answered a COUNT with a NOTICE, most likely because it
+    /// doesn't support NIP-45.
+    CountUnsupported(String),
+    /// The relay refused or ended the subscription with a CLOSED message.
+    SubscriptionClosed {
+        id: SubscriptionId,
+        message: String,
+    },
+    /// The relay rejected our AUTH event, with its reason.
+    Auth(String),
+    Tungstenite(tungstenite::Error),
+    /// `.onion` relays can only be reached through a proxy, see
+    /// `WsClientBuilder::proxy()`.
+    ProxyRequired,
+    Proxy(std::io::Error),
+    Tls(String),
+    /// Fail to fetch or parse the NIP-11 document of a relay.
+    RelayInfo(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Parse(e) => write!(f, "invalid relay url: {e}"),
+            Error::DnsResolution { host } => write!(f, "fail to resolve relay host {host}"),
+            Error::Listen => write!(f, "fail to start the listener"),
+            Error::Send => write!(f, "fail to send message to the listener"),
+            Error::SendQueueFull => write!(f, "the relay send queues are full"),
+            Error::Receive => write!(f, "fail to receive message from the listener"),
+            Error::NonBlocking => write!(f, "fail to set the socket non-blocking"),
+            Error::NotConnected => write!(f, "not connected"),
+            Error::KeysMissing => write!(f, "keys missing"),
+            Error::ArgMissing => write!(f, "relay missing"),
+            Error::Nip04Encrypt => write!(f, "NIP-04 encryption failed"),
+            Error::Nip04Decrypt => write!(f, "NIP-04 decryption failed"),
+            Error::NotNip04 => write!(f, "not a NIP-04 payload"),
+            Error::Nip44Encrypt => write!(f, "NIP-44 encryption failed"),
+            Error::Nip44Decrypt => write!(f, "NIP-44 decryption failed"),
+            Error::NotNip44 => write!(f, "not a NIP-44 payload"),
+            Error::GiftWrap => write!(f, "NIP-59 gift wrap failed"),
+            Error::Unwrap => write!(f, "NIP-59 unwrap failed"),
+            Error::SenderMismatch => write!(f, "seal and rumor authors differ"),
+            Error::SignEvent => write!(f, "fail to sign event"),
+            Error::NoAuthChallenge => write!(f, "no auth challenge received"),
+            Error::ConnectionClosed => write!(f, "connection closed by the relay"),
+            Error::ReconnectFailed => write!(f, "fail to reconnect to the relay"),
+            Error::RawRelayMessage => write!(f, "invalid relay message"),
+            Error::RelayMessage => write!(f, "unsupported relay message"),
+            Error::FilterNarrowed { detail } => write!(f, "filter narrowed by the relay: {detail}"),
+            Error::InvalidTimeRange => write!(f, "since is after until"),
+            Error::InvalidPongTimeout => write!(f, "pong timeout must exceed the ping interval"),
+            Error::InvalidRelayUrl(url) => write!(f, "not a websocket url: {url}"),
+            Error::MissingIdentifier(kind) => {
+                write!(f, "addressable event of kind {kind} has no `d` tag")
+            }
+            Error::KeyGeneration => write!(f, "fail to generate keys"),
+            Error::InvalidBech32 => write!(f, "invalid bech32 string"),
+            Error::Cancelled => write!(f, "cancelled"),
+            Error::SubscriptionNotFound(id) => write!(f, "unknown subscription {id}"),
+            Error::RelayNotFound(relay) => write!(f, "unknown relay {relay}"),
+            Error::PublishTimeout => write!(f, "no OK received for the published event"),
+            Error::EoseTimeout => write!(f, "no EOSE received for the subscription"),
+            Error::CountTimeout => write!(f, "no COUNT received for the request"),
+            Error::PowTimeout => write!(f, "proof of work not found in time"),
+            Error::Rejected(reason) => write!(f, "event rejected: {reason}"),
+            Error::InvalidCoordinate(c) => write!(f, "invalid event coordinate {c}"),
+            Error::InvalidEvent(id) => write!(f, "invalid id or signature for event {id}"),
+            Error::CountUnsupported(notice) => write!(f, "COUNT refused by the relay: {notice}"),
+            Error::SubscriptionClosed { id, message } => {
+                write!(f, "subscription {id} closed by the relay: {message}")
+            }
+            Error::Auth(reason) => write!(f, "authentication rejected: {reason}"),
+            Error::Tungstenite(e) => write!(f, "websocket error: {e}"),
+            Error::ProxyRequired => write!(f, "a proxy is required to reach .onion relays"),
+            Error::Proxy(e) => write!(f, "SOCKS5 proxy error: {e}"),
+            Error::Tls(e) => write!(f, "TLS handshake failed: {e}"),
+            Error::RelayInfo(e) => write!(f, "fail to fetch relay information: {e}"),
+        }
+    }
 }
 
-impl From<WebSocketError> for Error {
-    fn from(value: WebSocketError) -> Self {
-        Self::WebSocket(value)
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Parse(e) => Some(e),
+            Error::Proxy(e) => Some(e),
+            Error::Tungstenite(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<tungstenite::Error> for Error {
+    fn from(value: tungstenite::Error) -> Self {
+        Self::Tungstenite(value)
     }
 }
 
@@ -56,363 +241,4426 @@ impl From<ParseError> for Error {
 }
 
 type Message = String;
+type Socket = WebSocket<Stream>;
 
+// the TCP connection to a relay, TLS for wss relays
 #[derive(Debug)]
-pub enum SendMsg {
-    Msg(Message),
-    Stop,
+enum Stream {
+    Plain(TcpStream),
+    Tls(TlsStream<TcpStream>),
 }
 
-#[derive(Debug)]
-pub enum RecvMsg {
-    Close,
-    Msg(Message),
+impl Stream {
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(tcp) => tcp,
+            Stream::Tls(tls) => tls.get_ref(),
+        }
+    }
 }
 
-pub struct WsClient {
-    client: Option<Client<Box<dyn NetworkStream + Send>>>,
-    sender: Sender<SendMsg>,
-    ws_receiver: Option<Receiver<SendMsg>>,
-    receiver: Receiver<RecvMsg>,
-    ws_sender: Option<Sender<RecvMsg>>,
-    connected: bool,
-    relay: String,
-    keys: Keys,
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Plain(tcp) => tcp.read(buf),
+            Stream::Tls(tls) => tls.read(buf),
+        }
+    }
 }
 
-impl Debug for WsClient {
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Plain(tcp) => tcp.write(buf),
+            Stream::Tls(tls) => tls.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Plain(tcp) => tcp.flush(),
+            Stream::Tls(tls) => tls.flush(),
+        }
+    }
+}
+
+/// Sort order of `created_at`. Only applies to the events returned by the
+/// query helpers: events of a live subscription arrive in relay order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    Ascending,
+    Descending,
+}
+
+#[derive(Debug, Clone)]
+pub struct QueryOptions {
+    pub limit: Option<usize>,
+    pub timeout: Duration,
+    pub close_on_eose: bool,
+    /// Sort the events instead of keeping the order they were received.
+    pub order: Option<Order>,
+}
+
+impl Default for QueryOptions {
+    fn default() -> Self {
+        QueryOptions {
+            limit: None,
+            timeout: Duration::from_secs(10),
+            close_on_eose: true,
+            order: None,
+        }
+    }
+}
+
+// how to open the websocket, kept to reconnect or switch relay
+#[derive(Clone)]
+struct ConnectOptions {
+    auth_token: Option<String>,
+    user_agent: String,
+    headers: Vec<(String, String)>,
+    tcp_nodelay: bool,
+    tcp_keepalive: Option<Duration>,
+    ping_interval: Duration,
+    pong_timeout: Duration,
+    proxy: Option<SocketAddr>,
+    insecure_tls: HashSet<String>,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        ConnectOptions {
+            auth_token: None,
+            user_agent: format!("simple_nostr_client/{}", env!("CARGO_PKG_VERSION")),
+            headers: Vec::new(),
+            tcp_nodelay: true,
+            tcp_keepalive: None,
+            ping_interval: PING_INTERVAL,
+            pong_timeout: PONG_TIMEOUT,
+            proxy: None,
+            insecure_tls: HashSet::new(),
+        }
+    }
+}
+
+impl Debug for ConnectOptions {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("WsClient")
-            .field("connected", &self.connected)
-            .field("relay", &self.relay)
-            .field("keys", &self.keys)
+        f.debug_struct("ConnectOptions")
+            .field("auth_token", &self.auth_token.as_ref().map(|_| "***"))
+            .field("user_agent", &self.user_agent)
+            // values can be cookies
+            .field(
+                "headers",
+                &self.headers.iter().map(|(n, _)| n).collect::<Vec<_>>(),
+            )
+            .field("tcp_nodelay", &self.tcp_nodelay)
+            .field("tcp_keepalive", &self.tcp_keepalive)
+            .field("ping_interval", &self.ping_interval)
+            .field("pong_timeout", &self.pong_timeout)
+            .field("proxy", &self.proxy)
+            .field("insecure_tls", &self.insecure_tls)
             .finish()
     }
 }
 
-#[derive(Debug, Default, Clone)]
-pub struct WsClientBuilder {
-    relay: Option<String>,
-    keys: Option<Keys>,
+#[derive(Debug)]
+struct SubscriptionLatency {
+    sent: Instant,
+    first_event: Option<Duration>,
+    eose: Option<Duration>,
 }
 
-impl WsClientBuilder {
-    pub fn relay<T: Into<String>>(mut self, relay: T) -> Self {
-        self.set_relay(relay);
-        self
+impl SubscriptionLatency {
+    fn new() -> Self {
+        SubscriptionLatency {
+            sent: Instant::now(),
+            first_event: None,
+            eose: None,
+        }
     }
 
-    pub fn set_relay<T: Into<String>>(&mut self, relay: T) {
-        self.relay = Some(relay.into());
+    fn first_event(&mut self) {
+        if self.first_event.is_none() {
+            self.first_event = Some(self.sent.elapsed());
+        }
     }
 
-    pub fn get_relay(&self) -> Option<String> {
-        self.relay.clone()
+    fn eose(&mut self) {
+        if self.eose.is_none() {
+            let elapsed = self.sent.elapsed();
+            self.first_event.get_or_insert(elapsed);
+            self.eose = Some(elapsed);
+        }
     }
+}
 
-    pub fn keys(mut self, keys: Keys) -> Self {
-        self.keys = Some(keys);
-        self
-    }
+#[derive(Debug)]
+struct ProcessingTime {
+    budget: Option<Duration>,
+    samples: VecDeque<Duration>,
+    total: Duration,
+}
 
-    pub fn set_keys(&mut self, keys: Keys) {
-        self.keys = Some(keys);
+impl ProcessingTime {
+    fn new(budget: Option<Duration>) -> Self {
+        ProcessingTime {
+            budget,
+            samples: VecDeque::with_capacity(PROCESSING_SAMPLES),
+            total: Duration::ZERO,
+        }
     }
 
-    pub fn get_keys(&self) -> Option<&Keys> {
-        self.keys.as_ref()
+    fn record(&mut self, elapsed: Duration) {
+        if self.samples.len() == PROCESSING_SAMPLES {
+            if let Some(old) = self.samples.pop_front() {
+                self.total -= old;
+            }
+        }
+        self.samples.push_back(elapsed);
+        self.total += elapsed;
     }
 
-    pub fn connect(self) -> Result<WsClient, Error> {
-        let (url, keys) = if let (Some(url), Some(keys)) = (self.relay, self.keys) {
-            (url, keys)
+    fn average(&self) -> Option<Duration> {
+        if self.samples.is_empty() {
+            None
         } else {
-            return Err(Error::ArgMissing);
-        };
-        let client = ClientBuilder::new(&url)?.connect(None)?;
-        client
-            .set_nonblocking(true)
-            .map_err(|_| Error::NonBlocking)?;
-        let (sender, ws_receiver) = mpsc::channel();
-        let (ws_sender, receiver) = mpsc::channel();
-        let mut client = WsClient {
-            client: Some(client),
-            sender,
-            ws_receiver: Some(ws_receiver),
-            receiver,
-            ws_sender: Some(ws_sender),
-            connected: false,
-            relay: url,
-            keys,
-        };
-        client.listen()?;
-        Ok(client)
+            Some(self.total / self.samples.len() as u32)
+        }
     }
 }
 
-impl WsClient {
-    #[allow(clippy::new_ret_no_self)]
-    pub fn new() -> WsClientBuilder {
-        WsClientBuilder::default()
+/// Outcome per relay of `WsClient::publish_to_all()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublishReport {
+    pub id: EventId,
+    pub accepted: Vec<String>,
+    /// Relays that rejected the event, with the reason they gave.
+    pub rejected: Vec<(String, String)>,
+    /// Relays that did not answer in time.
+    pub timed_out: Vec<String>,
+}
+
+/// Counters since the client connected, see `WsClient::stats()`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientStats {
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub events_received: u64,
+    pub pings: u64,
+    pub pongs: u64,
+    pub reconnects: u64,
+    /// See `WsClient::dropped_events()`.
+    pub dropped_events: u64,
+    /// See `WsClient::late_events()`.
+    pub late_events: u64,
+}
+
+// shared with the listeners
+#[derive(Debug, Default)]
+struct Stats {
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    events_received: AtomicU64,
+    pings: AtomicU64,
+    pongs: AtomicU64,
+    reconnects: AtomicU64,
+    dropped_events: AtomicU64,
+    late_events: AtomicU64,
+}
+
+impl Stats {
+    fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
     }
 
-    pub fn pubkey(&self) -> PublicKey {
-        self.keys.public_key
+    fn snapshot(&self) -> ClientStats {
+        ClientStats {
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            events_received: self.events_received.load(Ordering::Relaxed),
+            pings: self.pings.load(Ordering::Relaxed),
+            pongs: self.pongs.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            dropped_events: self.dropped_events.load(Ordering::Relaxed),
+            late_events: self.late_events.load(Ordering::Relaxed),
+        }
     }
+}
 
-    fn listen(&mut self) -> Result<(), Error> {
-        if let (Some(client), Some(sender), Some(receiver)) = (
-            self.client.take(),
-            self.ws_sender.take(),
-            self.ws_receiver.take(),
-        ) {
-            std::thread::spawn(|| listen(client, sender, receiver));
-            self.connected = true;
-            Ok(())
-        } else {
-            Err(Error::Listen)
+// ids of the last `capacity` events received, oldest evicted first
+#[derive(Debug)]
+struct SeenEvents {
+    capacity: usize,
+    ids: HashSet<EventId>,
+    order: VecDeque<EventId>,
+}
+
+impl SeenEvents {
+    fn new(capacity: usize) -> Self {
+        SeenEvents {
+            capacity,
+            ids: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
         }
     }
 
-    pub fn encrypt<T>(&mut self, receiver: &PublicKey, content: T) -> Result<String, Error>
-    where
-        T: AsRef<[u8]>,
-    {
-        nip04::encrypt(self.get_keys().secret_key(), receiver, content)
-            .map_err(|_| Error::Nip04Encrypt)
+    fn contains(&self, id: &EventId) -> bool {
+        self.ids.contains(id)
     }
 
-    pub fn decrypt_dm(&mut self, mut event: Event) -> Result<Event, Error> {
-        if event.kind != Kind::EncryptedDirectMessage {
-            return Err(Error::NotNip04);
+    // returns false if `id` was already seen
+    fn insert(&mut self, id: EventId) -> bool {
+        if !self.ids.insert(id) {
+            return false;
         }
-        let content = self.decrypt(&event.pubkey, event.content)?;
-        event.content = content;
-        Ok(event)
+        if self.order.len() == self.capacity {
+            if let Some(old) = self.order.pop_front() {
+                self.ids.remove(&old);
+            }
+        }
+        self.order.push_back(id);
+        true
     }
+}
 
-    pub fn decrypt(&mut self, event_pubkey: &PublicKey, content: String) -> Result<String, Error> {
-        nip04::decrypt(self.get_keys().secret_key(), event_pubkey, content)
-            .map_err(|_| Error::Nip04Decrypt)
+#[derive(Debug)]
+pub enum SendMsg {
+    Msg(Message),
+    Stop,
+}
+
+// messages from a relay carry its url
+#[derive(Debug)]
+pub enum RecvMsg {
+    Close(String),
+    Msg(String, Message),
+    Batch(String, Vec<Message>),
+    /// A pong matching the last ping was received at this time, after this
+    /// round trip.
+    Pong(String, SystemTime, Duration),
+    /// The listener reconnected to the relay and is about to replay the
+    /// subscriptions.
+    Reconnected(String),
+    /// Wakes up a blocking receive, see `Canceller`.
+    Cancel,
+}
+
+/// State transitions of a relay connection, see `WsClient::connection_events()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    Disconnected,
+    Reconnecting,
+    /// The listener stopped, the relay won't be reconnected.
+    Closed,
+}
+
+type StateSenders = Arc<Mutex<Vec<Sender<(String, ConnectionState)>>>>;
+
+/// What the client signs events with, see `WsClient::signer_kind()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignerKind {
+    /// Local keys, the client can post.
+    Local,
+    /// No keys, the client can only read.
+    ReadOnly,
+    /// An external signer (e.g. NIP-46). Unused for now: the client only
+    /// signs with local keys.
+    Remote,
+}
+
+/// How events are spread over the relays, see `WsClientBuilder::publish_mode()`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PublishMode {
+    /// Every event goes to every connected relay.
+    #[default]
+    Broadcast,
+    /// Each event goes to the next connected relay in turn.
+    RoundRobin,
+}
+
+/// Encryption scheme of the direct messages sent with `WsClient::send_dm()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encryption {
+    Nip04,
+    Nip44,
+}
+
+/// NIP-65 usage of a relay in a relay list, no marker means both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayMarker {
+    Read,
+    Write,
+}
+
+impl RelayMarker {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RelayMarker::Read => "read",
+            RelayMarker::Write => "write",
+        }
     }
+}
 
-    pub fn subscribe_dm(&mut self) -> Result<(), Error> {
-        self.is_connected()?;
-        let filter = Filter::new()
-            .kind(Kind::EncryptedDirectMessage)
-            .pubkey(self.get_keys().public_key());
-        let msg = nostr::ClientMessage::req(SubscriptionId::generate(), vec![filter]);
-        self.send_raw(msg.as_json())?;
-        Ok(())
+// a relay message put aside by a blocking helper, for `try_receive()`
+#[derive(Debug)]
+struct Deferred {
+    relay: String,
+    msg: RelayMessage,
+    raw: Option<Message>,
+    // AUTH, OK and NOTICE bookkeeping already done
+    handled: bool,
+}
+
+/// Unblocks a pending `WsClient::receive_timeout()` or `WsClient::query()`,
+/// which then return `Error::Cancelled`, or ends `WsClient::events()`. A cancel while nothing waits has
+/// no effect on the next wait.
+#[derive(Debug, Clone)]
+pub struct Canceller {
+    cancelled: Arc<AtomicBool>,
+    sender: SyncSender<RecvMsg>,
+}
+
+impl Canceller {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        // a full channel wakes the receiver up anyway
+        let _ = self.sender.try_send(RecvMsg::Cancel);
     }
+}
 
-    pub fn subscribe_pool(&mut self, back: u64) -> Result<(), Error> {
-        self.is_connected()?;
-        let since = Timestamp::now() - Timestamp::from_secs(back);
-        let filter = Filter::new().kind(Kind::Custom(2022)).since(since);
-        let msg = nostr::ClientMessage::req(SubscriptionId::generate(), vec![filter]);
-        self.send_raw(msg.as_json())?;
-        Ok(())
+/// Callback receiving the relay url, see `WsClientBuilder::on_reconnect`.
+pub type Hook = Box<dyn FnMut(&str) + Send>;
+
+/// Callback receiving a signed auth event right before it is sent.
+pub type AuthHook = Box<dyn FnMut(&Event) + Send>;
+
+/// Callback receiving the listener logs, see `WsClientBuilder::on_log`.
+pub type LogHook = Box<dyn FnMut(log::Level, String) + Send>;
+
+/// Callback run once a subscription reached EOSE, see `WsClient::on_eose()`.
+pub type EoseHook = Box<dyn FnMut() + Send>;
+
+#[derive(Clone, Default)]
+pub struct Hooks {
+    on_reconnect: Option<Arc<Mutex<Hook>>>,
+    on_disconnect: Option<Arc<Mutex<Hook>>>,
+    on_auth: Option<Arc<Mutex<AuthHook>>>,
+    on_log: Option<Arc<Mutex<LogHook>>>,
+}
+
+impl Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hooks")
+            .field("on_reconnect", &self.on_reconnect.is_some())
+            .field("on_disconnect", &self.on_disconnect.is_some())
+            .field("on_auth", &self.on_auth.is_some())
+            .field("on_log", &self.on_log.is_some())
+            .finish()
     }
+}
 
-    pub fn send_dm<T: Into<String>>(
-        &mut self,
-        content: T,
-        receiver: &PublicKey,
-    ) -> Result<(), Error> {
-        let content = self.encrypt(receiver, content.into())?;
-        let dm = EventBuilder::new(
-            Kind::EncryptedDirectMessage,
-            content,
-            vec![Tag::public_key(*receiver)],
-        );
-        self.post_event(dm)
+impl Hooks {
+    fn call(hook: &Option<Arc<Mutex<Hook>>>, relay: &str) {
+        if let Some(hook) = hook {
+            match hook.lock() {
+                Ok(mut hook) => hook(relay),
+                Err(_) => log::error!("Hooks::call(): hook poisoned"),
+            }
+        }
     }
 
-    fn send_raw(&mut self, msg: Message) -> Result<(), Error> {
-        self.is_connected()?;
-        self.sender.send(SendMsg::Msg(msg)).map_err(|_| Error::Send)
+    fn disconnected(&self, relay: &str) {
+        Self::call(&self.on_disconnect, relay);
     }
 
-    fn try_receive_raw(&mut self) -> Result<Option<RecvMsg>, Error> {
-        self.is_connected()?;
-        let msg = match self.receiver.try_recv() {
-            Ok(m) => Ok(Some(m)),
-            Err(e) => match e {
-                mpsc::TryRecvError::Empty => Ok(None),
-                mpsc::TryRecvError::Disconnected => Err(Error::Receive),
-            },
-        };
-        if let Ok(Some(RecvMsg::Close)) = msg {
-            self.connected = false;
+    fn reconnected(&self, relay: &str) {
+        Self::call(&self.on_reconnect, relay);
+    }
+
+    fn auth(&self, event: &Event) {
+        if let Some(hook) = &self.on_auth {
+            match hook.lock() {
+                Ok(mut hook) => hook(event),
+                Err(_) => log::error!("Hooks::auth(): hook poisoned"),
+            }
         }
-        msg
     }
+}
 
-    pub fn try_receive(&mut self) -> Result<Option<Event>, Error> {
-        match self.try_receive_raw()? {
-            Some(m) => match m {
-                RecvMsg::Close => Err(Error::ConnectionClosed),
-                RecvMsg::Msg(t) => match RawRelayMessage::from_json(t) {
-                    Ok(rrm) => match RelayMessage::try_from(rrm) {
-                        Ok(rm) => match rm {
-                            RelayMessage::Event { event, .. } => {
-                                #[allow(deprecated)]
-                                if event.kind() == Kind::EncryptedDirectMessage {
-                                    let event = self.decrypt_dm(*event)?;
-                                    Ok(Some(event))
-                                } else {
-                                    Ok(Some(*event))
-                                }
-                            }
-                            RelayMessage::Auth { .. } => {
-                                log::error!("unexpected auth message");
-                                Ok(None)
-                            }
-                            _ => Ok(None),
-                        },
-                        Err(_) => Err(Error::RelayMessage),
-                    },
-                    Err(_) => Err(Error::RawRelayMessage),
-                },
+type Reconnect = Arc<Mutex<Box<dyn ReconnectStrategy>>>;
+
+#[derive(Clone, Default)]
+pub struct ListenConfig {
+    relay: String,
+    label: String,
+    hooks: Hooks,
+    bandwidth_limit: Option<u64>,
+    batch_size: usize,
+    paused: Arc<AtomicBool>,
+    options: ConnectOptions,
+    reconnect: Option<Reconnect>,
+    states: StateSenders,
+    stats: Arc<Stats>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl ListenConfig {
+    // route the listener logs to the `on_log` hook if any
+    fn log(&self, level: log::Level, args: std::fmt::Arguments) {
+        match &self.hooks.on_log {
+            Some(hook) => match hook.lock() {
+                Ok(mut hook) => hook(level, format!("[{}] {}", self.label, args)),
+                Err(_) => log::error!("ListenConfig::log(): hook poisoned"),
             },
-            None => Ok(None),
+            None => log::log!(level, "[{}] {}", self.label, args),
         }
     }
 
-    pub fn stop(&mut self) {
-        if self.connected {
-            self.connected = false;
-            _ = self.sender.send(SendMsg::Stop);
+    fn notify(&self, state: ConnectionState) {
+        if let Ok(mut senders) = self.states.lock() {
+            senders.retain(|s| s.send((self.relay.clone(), state)).is_ok());
         }
     }
+}
 
-    pub fn get_relay(&self) -> String {
-        self.relay.clone()
+impl Debug for ListenConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ListenConfig")
+            .field("relay", &self.relay)
+            .field("label", &self.label)
+            .field("hooks", &self.hooks)
+            .field("bandwidth_limit", &self.bandwidth_limit)
+            .field("batch_size", &self.batch_size)
+            .field("paused", &self.paused)
+            .field("options", &self.options)
+            .field("reconnect", &self.reconnect.is_some())
+            .field("stats", &self.stats)
+            .finish()
     }
+}
 
-    pub fn is_connected(&self) -> Result<(), Error> {
-        if self.connected {
-            Ok(())
-        } else {
-            Err(Error::NotConnected)
+#[derive(Debug)]
+struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate,
+            tokens: rate as f64,
+            last: Instant::now(),
         }
     }
 
-    pub fn get_keys(&self) -> &Keys {
-        &self.keys
+    fn available(&mut self) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate as f64;
+        self.tokens = (self.tokens + refill).min(self.rate as f64);
+        self.last = now;
+        self.tokens > 0.0
     }
 
-    pub fn post_event(&mut self, event: EventBuilder) -> Result<(), Error> {
-        self.is_connected()?;
-        let event = event
-            .to_event(self.get_keys())
-            .map_err(|_| Error::SignEvent)?;
-        let msg = ClientMessage::event(event);
-        log::debug!("_post_event() msg: {:?}", msg);
-        self.send_raw(msg.as_json())
+    // a message bigger than the bucket is let through and paid back later
+    fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
     }
 }
 
-impl Drop for WsClient {
-    fn drop(&mut self) {
-        self.stop();
+fn parse_relay_message(msg: &str) -> Result<RelayMessage, Error> {
+    match RawRelayMessage::from_json(msg) {
+        Ok(rrm) => match RelayMessage::try_from(rrm) {
+            Ok(rm) => Ok(rm),
+            Err(_) => Err(Error::RelayMessage),
+        },
+        Err(_) => Err(Error::RawRelayMessage),
     }
 }
 
-pub fn listen(
-    mut client: Client<Box<dyn NetworkStream + Send>>,
-    sender: Sender<RecvMsg>,
-    receiver: Receiver<SendMsg>,
-) {
-    let mut last_ping = SystemTime::now();
-    let mut last_pong = SystemTime::now();
-    let mut ping_nonce = 0u8;
-    loop {
-        let mut wait = true;
-        match receiver.try_recv() {
-            Ok(m) => match m {
-                SendMsg::Msg(m) => {
-                    wait = false;
-                    if let Err(e) = client.send_message(&websocket::Message::text(m)) {
-                        log::error!("listen(): fail to send message: {:?}", e);
-                    }
-                }
-                SendMsg::Stop => return,
-            },
-            Err(mpsc::TryRecvError::Empty) => {}
-            _ => return,
-        }
-
-        match client.recv_message() {
-            Ok(m) => {
-                wait = false;
-                match m {
-                    OwnedMessage::Text(m) => {
-                        log::debug!("recv text: {:?}", m);
-                        let _ = sender.send(RecvMsg::Msg(m));
-                    }
-                    OwnedMessage::Binary(m) => {
-                        log::error!("listen() unexpected binary message {:?}", m);
-                    }
-                    OwnedMessage::Close(_) => {
-                        log::debug!("recv: Close ");
-                        sender.send(RecvMsg::Close).expect("main thread panicked");
-                    }
-                    OwnedMessage::Ping(nonce) => {
-                        _ = client.send_message(&OwnedMessage::Pong(nonce));
-                    }
-                    OwnedMessage::Pong(_) => {
-                        last_pong = SystemTime::now();
-                    }
-                }
-            }
-            Err(e) => match e {
-                WebSocketError::ProtocolError(_e) => {
-                    // FIXME:: why do we receive a bunch of protocols errors at startup?
-                    // log::error!("ProtocolError: {:?}", e);
-                }
-                WebSocketError::DataFrameError(_e) => {
-                    // FIXME: why do we receive a bunch of "Expected unmasked data frame" at startup?
-                    // log::error!("DataFrameError: {:?}", e);
-                }
-                WebSocketError::NoDataAvailable => {}
-                WebSocketError::IoError(e) => {
-                    if e.kind() == ErrorKind::WouldBlock {
-                    } else {
-                        log::error!("{:?}", e);
-                    }
-                }
-                WebSocketError::Utf8Error(e) => {
-                    log::error!("{:?}", e);
-                }
-                WebSocketError::Other(e) => {
-                    log::error!("{:?}", e);
-                }
-            },
-        }
+fn tag_relay(relay: String, batch: Vec<Message>) -> impl Iterator<Item = (String, Message)> {
+    batch.into_iter().map(move |m| (relay.clone(), m))
+}
 
-        if SystemTime::now()
+// a relay connection, served by its own listener thread
+#[derive(Debug)]
+struct Connection {
+    relay: String,
+    sender: SyncSender<SendMsg>,
+    priority_sender: SyncSender<Message>,
+    stopped: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+    connected: bool,
+    last_pong: Option<SystemTime>,
+    latency: Option<Duration>,
+}
+
+impl Connection {
+    // the flag reaches the listener even if its queue is full
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        let _ = self.sender.try_send(SendMsg::Stop);
+    }
+}
+
+// queue `msg` for a listener without blocking the client
+fn try_queue<T>(sender: &SyncSender<T>, msg: T) -> Result<(), Error> {
+    sender.try_send(msg).map_err(|e| match e {
+        mpsc::TrySendError::Full(_) => Error::SendQueueFull,
+        mpsc::TrySendError::Disconnected(_) => Error::Send,
+    })
+}
+
+pub struct WsClient {
+    connections: Vec<Connection>,
+    receiver: Receiver<RecvMsg>,
+    ws_sender: SyncSender<RecvMsg>,
+    channel_capacity: usize,
+    label: Option<String>,
+    keys: Option<Keys>,
+    hooks: Hooks,
+    bandwidth_limit: Option<u64>,
+    cache_auth: bool,
+    auto_auth: bool,
+    auth_challenge: HashMap<String, String>,
+    last_auth: HashMap<String, (String, Event)>,
+    last_auth_relay: Option<String>,
+    seen_kinds: HashSet<Kind>,
+    pending: VecDeque<Deferred>,
+    // messages try_receive_for() met for other subscriptions (or none), at
+    // most `channel_capacity` per queue
+    routed: HashMap<Option<SubscriptionId>, VecDeque<Deferred>>,
+    // text of the last message returned by next_relay_message()
+    raw: Option<Message>,
+    // whether set_aside() already did its bookkeeping
+    handled: bool,
+    batched: VecDeque<(String, Message)>,
+    // relays that sent EOSE, per subscription
+    eose: HashMap<SubscriptionId, HashSet<String>>,
+    clock_offsets: VecDeque<i64>,
+    compensate_clock_skew: bool,
+    replay: VecDeque<Event>,
+    replay_capacity: usize,
+    latency: HashMap<SubscriptionId, SubscriptionLatency>,
+    strict_addressable: bool,
+    batch_size: usize,
+    #[cfg(feature = "store")]
+    store: Option<Arc<dyn EventStore>>,
+    subscriptions: HashMap<SubscriptionId, Vec<Filter>>,
+    in_flight: HashMap<EventId, Kind>,
+    processing: Option<ProcessingTime>,
+    strict_subscriptions: bool,
+    paused: Arc<AtomicBool>,
+    cancelled: Arc<AtomicBool>,
+    eose_hooks: HashMap<SubscriptionId, EoseHook>,
+    closed: HashSet<SubscriptionId>,
+    // closed by query(), whatever still comes for them was already returned
+    finished: VecDeque<SubscriptionId>,
+    accept_late_events: bool,
+    notices: VecDeque<String>,
+    reconnect: Option<Reconnect>,
+    options: ConnectOptions,
+    verify_events: bool,
+    states: StateSenders,
+    dedup: Option<SeenEvents>,
+    stats: Arc<Stats>,
+    publish_mode: PublishMode,
+    // connected relay the next round robin event goes to
+    next_relay: usize,
+    // returned by the next try_receive_all(), after the events drained along
+    drain_error: Option<Error>,
+    // taken by events() while blocking, not handled yet
+    received: Option<RecvMsg>,
+}
+
+impl Debug for WsClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WsClient")
+            .field("label", &self.label)
+            .field("connected", &self.is_connected().is_ok())
+            .field("relays", &self.get_relays())
+            .field("keys", &self.keys)
+            .finish()
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct WsClientBuilder {
+    relays: Vec<String>,
+    label: Option<String>,
+    keys: Option<Keys>,
+    hooks: Hooks,
+    cache_auth: bool,
+    auto_auth: bool,
+    bandwidth_limit: Option<u64>,
+    compensate_clock_skew: bool,
+    replay_buffer: usize,
+    strict_addressable: bool,
+    batch_size: usize,
+    measure_processing: bool,
+    processing_budget: Option<Duration>,
+    strict_subscriptions: bool,
+    accept_late_events: bool,
+    auto_reconnect: bool,
+    reconnect_strategy: Option<Reconnect>,
+    reconnect_backoff: Option<(Duration, Duration)>,
+    max_reconnect_attempts: Option<u32>,
+    #[cfg(feature = "store")]
+    store: Option<Arc<dyn EventStore>>,
+    options: ConnectOptions,
+    skip_verification: bool,
+    dedup: usize,
+    channel_capacity: Option<usize>,
+    publish_mode: PublishMode,
+}
+
+impl Debug for WsClientBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut s = f.debug_struct("WsClientBuilder");
+        s.field("relays", &self.relays)
+            .field("label", &self.label)
+            .field("keys", &self.keys)
+            .field("hooks", &self.hooks)
+            .field("cache_auth", &self.cache_auth)
+            .field("auto_auth", &self.auto_auth)
+            .field("bandwidth_limit", &self.bandwidth_limit)
+            .field("compensate_clock_skew", &self.compensate_clock_skew)
+            .field("replay_buffer", &self.replay_buffer)
+            .field("strict_addressable", &self.strict_addressable)
+            .field("batch_size", &self.batch_size)
+            .field("measure_processing", &self.measure_processing)
+            .field("processing_budget", &self.processing_budget)
+            .field("strict_subscriptions", &self.strict_subscriptions)
+            .field("accept_late_events", &self.accept_late_events)
+            .field("auto_reconnect", &self.auto_reconnect)
+            .field("reconnect_strategy", &self.reconnect_strategy.is_some())
+            .field("reconnect_backoff", &self.reconnect_backoff)
+            .field("max_reconnect_attempts", &self.max_reconnect_attempts)
+            .field("options", &self.options)
+            .field("verify_events", &!self.skip_verification)
+            .field("dedup", &self.dedup)
+            .field("channel_capacity", &self.channel_capacity)
+            .field("publish_mode", &self.publish_mode);
+        #[cfg(feature = "store")]
+        s.field("store", &self.store.is_some());
+        s.finish()
+    }
+}
+
+impl WsClientBuilder {
+    /// Add a relay to connect to, can be called several times.
+    pub fn relay<T: Into<String>>(mut self, relay: T) -> Self {
+        self.set_relay(relay);
+        self
+    }
+
+    pub fn set_relay<T: Into<String>>(&mut self, relay: T) {
+        self.relays.push(relay.into());
+    }
+
+    /// The first relay added.
+    pub fn get_relay(&self) -> Option<String> {
+        self.relays.first().cloned()
+    }
+
+    /// Replace the relays to connect to.
+    pub fn relays(mut self, relays: Vec<String>) -> Self {
+        self.set_relays(relays);
+        self
+    }
+
+    pub fn set_relays(&mut self, relays: Vec<String>) {
+        self.relays = relays;
+    }
+
+    pub fn get_relays(&self) -> Vec<String> {
+        self.relays.clone()
+    }
+
+    /// Name used for this connection in logs and `Debug` output, defaults to
+    /// the relay url. Logs about a given relay show `label url`.
+    pub fn label<T: Into<String>>(mut self, label: T) -> Self {
+        self.set_label(label);
+        self
+    }
+
+    pub fn set_label<T: Into<String>>(&mut self, label: T) {
+        self.label = Some(label.into());
+    }
+
+    pub fn get_label(&self) -> Option<String> {
+        self.label.clone()
+    }
+
+    pub fn keys(mut self, keys: Keys) -> Self {
+        self.keys = Some(keys);
+        self
+    }
+
+    pub fn set_keys(&mut self, keys: Keys) {
+        self.keys = Some(keys);
+    }
+
+    pub fn get_keys(&self) -> Option<&Keys> {
+        self.keys.as_ref()
+    }
+
+    /// Use freshly generated keys, failing with `Error::KeyGeneration` instead
+    /// of panicking if the OS can't provide entropy.
+    pub fn ephemeral_keys(mut self) -> Result<Self, Error> {
+        let mut seed = [0u8; 32];
+        OsRng
+            .try_fill_bytes(&mut seed)
+            .map_err(|_| Error::KeyGeneration)?;
+        self.keys = Some(keys_from_seed(seed)?);
+        Ok(self)
+    }
+
+    /// Derive the keys from `seed`, meant for deterministic tests: anyone
+    /// knowing the seed holds the secret key, never use it with a guessable
+    /// or reused seed for a real identity.
+    pub fn keys_from_seed(mut self, seed: [u8; 32]) -> Result<Self, Error> {
+        self.keys = Some(keys_from_seed(seed)?);
+        Ok(self)
+    }
+
+    /// Use the keys of the NIP-19 `nsec1...` secret key.
+    pub fn keys_from_nsec(mut self, nsec: &str) -> Result<Self, Error> {
+        let secret_key = SecretKey::from_bech32(nsec).map_err(|_| Error::InvalidBech32)?;
+        self.keys = Some(Keys::new(secret_key));
+        Ok(self)
+    }
+
+    /// Called from the listener thread with the relay url each time a
+    /// reconnection succeeds.
+    pub fn on_reconnect(mut self, hook: Hook) -> Self {
+        self.set_on_reconnect(hook);
+        self
+    }
+
+    pub fn set_on_reconnect(&mut self, hook: Hook) {
+        self.hooks.on_reconnect = Some(Arc::new(Mutex::new(hook)));
+    }
+
+    /// Called from the listener thread with the relay url when the relay
+    /// closes the connection or stops answering pings.
+    pub fn on_disconnect(mut self, hook: Hook) -> Self {
+        self.set_on_disconnect(hook);
+        self
+    }
+
+    pub fn set_on_disconnect(&mut self, hook: Hook) {
+        self.hooks.on_disconnect = Some(Arc::new(Mutex::new(hook)));
+    }
+
+    /// Called with every auth event before it is sent to the relay.
+    pub fn on_auth(mut self, hook: AuthHook) -> Self {
+        self.set_on_auth(hook);
+        self
+    }
+
+    pub fn set_on_auth(&mut self, hook: AuthHook) {
+        self.hooks.on_auth = Some(Arc::new(Mutex::new(hook)));
+    }
+
+    /// Receive the logs of the listener threads (errors, received messages,
+    /// reconnections) instead of the `log` crate.
+    pub fn on_log(mut self, hook: LogHook) -> Self {
+        self.set_on_log(hook);
+        self
+    }
+
+    pub fn set_on_log(&mut self, hook: LogHook) {
+        self.hooks.on_log = Some(Arc::new(Mutex::new(hook)));
+    }
+
+    /// Replay the last signed auth event when the relay sends the same
+    /// challenge again instead of signing a new one.
+    pub fn cache_auth(mut self, cache: bool) -> Self {
+        self.cache_auth = cache;
+        self
+    }
+
+    pub fn set_cache_auth(&mut self, cache: bool) {
+        self.cache_auth = cache;
+    }
+
+    pub fn get_cache_auth(&self) -> bool {
+        self.cache_auth
+    }
+
+    /// Answer AUTH challenges (NIP-42) as soon as they are received instead
+    /// of waiting for `WsClient::authenticate()`. A rejected AUTH is returned
+    /// as `Error::Auth` by `try_receive()`.
+    pub fn auto_auth(mut self, auto: bool) -> Self {
+        self.auto_auth = auto;
+        self
+    }
+
+    pub fn set_auto_auth(&mut self, auto: bool) {
+        self.auto_auth = auto;
+    }
+
+    pub fn get_auto_auth(&self) -> bool {
+        self.auto_auth
+    }
+
+    /// Throttle each direction to `bytes_per_sec` bytes of message payload,
+    /// unlimited by default.
+    pub fn bandwidth_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.set_bandwidth_limit(bytes_per_sec);
+        self
+    }
+
+    pub fn set_bandwidth_limit(&mut self, bytes_per_sec: u64) {
+        self.bandwidth_limit = Some(bytes_per_sec).filter(|b| *b > 0);
+    }
+
+    pub fn get_bandwidth_limit(&self) -> Option<u64> {
+        self.bandwidth_limit
+    }
+
+    /// Backdate `created_at` of posted events by `WsClient::estimated_clock_skew()`
+    /// when it gets large enough for relays to reject them. Events whose
+    /// `created_at` is pinned with `EventBuilder::custom_created_at` are left
+    /// as is.
+    pub fn compensate_clock_skew(mut self, compensate: bool) -> Self {
+        self.compensate_clock_skew = compensate;
+        self
+    }
+
+    pub fn set_compensate_clock_skew(&mut self, compensate: bool) {
+        self.compensate_clock_skew = compensate;
+    }
+
+    pub fn get_compensate_clock_skew(&self) -> bool {
+        self.compensate_clock_skew
+    }
+
+    /// Keep the last `capacity` received events for `WsClient::recent_events()`,
+    /// 0 (default) disables it.
+    pub fn replay_buffer(mut self, capacity: usize) -> Self {
+        self.replay_buffer = capacity;
+        self
+    }
+
+    pub fn set_replay_buffer(&mut self, capacity: usize) {
+        self.replay_buffer = capacity;
+    }
+
+    pub fn get_replay_buffer(&self) -> usize {
+        self.replay_buffer
+    }
+
+    /// Return each event once even if several relays (or subscriptions) send
+    /// it, remembering the ids of the last `capacity` events. 0 (default)
+    /// disables it.
+    pub fn dedup(mut self, capacity: usize) -> Self {
+        self.set_dedup(capacity);
+        self
+    }
+
+    pub fn set_dedup(&mut self, capacity: usize) {
+        self.dedup = capacity;
+    }
+
+    pub fn get_dedup(&self) -> usize {
+        self.dedup
+    }
+
+    /// Number of relay messages queued for the client before the listeners
+    /// block, 10 000 by default. A client that stops receiving then stalls the
+    /// relay connections (which may time out) instead of growing memory
+    /// without limit. Also bounds the messages queued for each relay
+    /// (`Error::SendQueueFull` once reached) and the ones `try_receive_for()`
+    /// keeps for each other subscription.
+    pub fn channel_capacity(mut self, capacity: usize) -> Self {
+        self.set_channel_capacity(capacity);
+        self
+    }
+
+    pub fn set_channel_capacity(&mut self, capacity: usize) {
+        self.channel_capacity = Some(capacity);
+    }
+
+    pub fn get_channel_capacity(&self) -> usize {
+        self.channel_capacity.unwrap_or(CHANNEL_CAPACITY)
+    }
+
+    /// Send each event to every relay (the default) or to a single relay in
+    /// turn. Round robin cuts the redundant traffic when the relays mirror
+    /// each other, but an event then reaches only one relay and is lost if
+    /// that relay drops or rejects it. REQ, CLOSE and AUTH always go to every
+    /// relay, and `publish_to_all()` always broadcasts.
+    pub fn publish_mode(mut self, mode: PublishMode) -> Self {
+        self.set_publish_mode(mode);
+        self
+    }
+
+    pub fn set_publish_mode(&mut self, mode: PublishMode) {
+        self.publish_mode = mode;
+    }
+
+    pub fn get_publish_mode(&self) -> PublishMode {
+        self.publish_mode
+    }
+
+    /// Refuse to post addressable events without a `d` tag instead of only
+    /// logging a warning.
+    pub fn strict_addressable(mut self, strict: bool) -> Self {
+        self.strict_addressable = strict;
+        self
+    }
+
+    pub fn set_strict_addressable(&mut self, strict: bool) {
+        self.strict_addressable = strict;
+    }
+
+    pub fn get_strict_addressable(&self) -> bool {
+        self.strict_addressable
+    }
+
+    /// Let the listener forward up to `size` messages at once when several are
+    /// available, reducing channel overhead on busy subscriptions. 0 or 1
+    /// (default) forwards every message on its own.
+    pub fn batch_size(mut self, size: usize) -> Self {
+        self.batch_size = size;
+        self
+    }
+
+    pub fn set_batch_size(&mut self, size: usize) {
+        self.batch_size = size;
+    }
+
+    pub fn get_batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    /// Time the processing of every received event (decryption, storage...),
+    /// see `WsClient::processing_time()`. Disabled by default.
+    pub fn measure_processing(mut self, measure: bool) -> Self {
+        self.measure_processing = measure;
+        self
+    }
+
+    pub fn set_measure_processing(&mut self, measure: bool) {
+        self.measure_processing = measure;
+    }
+
+    pub fn get_measure_processing(&self) -> bool {
+        self.measure_processing
+    }
+
+    /// Log a warning for every event taking longer than `budget` to process,
+    /// implies `measure_processing(true)`. Events are never dropped.
+    pub fn processing_budget(mut self, budget: Duration) -> Self {
+        self.set_processing_budget(budget);
+        self
+    }
+
+    pub fn set_processing_budget(&mut self, budget: Duration) {
+        self.processing_budget = Some(budget);
+    }
+
+    pub fn get_processing_budget(&self) -> Option<Duration> {
+        self.processing_budget
+    }
+
+    /// Drop events received under a subscription id the client did not open
+    /// (or already closed), see `WsClient::dropped_events()`. Disabled by
+    /// default.
+    pub fn strict_subscriptions(mut self, strict: bool) -> Self {
+        self.strict_subscriptions = strict;
+        self
+    }
+
+    pub fn set_strict_subscriptions(&mut self, strict: bool) {
+        self.strict_subscriptions = strict;
+    }
+
+    pub fn get_strict_subscriptions(&self) -> bool {
+        self.strict_subscriptions
+    }
+
+    /// Check the id and signature of every received event, failing with
+    /// `Error::InvalidEvent` on forged ones. Enabled by default.
+    pub fn verify_events(mut self, verify: bool) -> Self {
+        self.set_verify_events(verify);
+        self
+    }
+
+    pub fn set_verify_events(&mut self, verify: bool) {
+        self.skip_verification = !verify;
+    }
+
+    pub fn get_verify_events(&self) -> bool {
+        !self.skip_verification
+    }
+
+    /// Return (with a warning) events the relay sends for a subscription after
+    /// CLOSED it, instead of ignoring them (default). Either way they are
+    /// counted by `WsClient::late_events()`.
+    pub fn accept_late_events(mut self, accept: bool) -> Self {
+        self.accept_late_events = accept;
+        self
+    }
+
+    pub fn set_accept_late_events(&mut self, accept: bool) {
+        self.accept_late_events = accept;
+    }
+
+    pub fn get_accept_late_events(&self) -> bool {
+        self.accept_late_events
+    }
+
+    /// Reconnect when a relay closes the connection or stops answering
+    /// pings, then replay the open subscriptions. Attempts are paced by an
+    /// exponential backoff with jitter from 1s to 1min, see
+    /// `reconnect_backoff()` and `reconnect_strategy()`. Disabled by default.
+    pub fn auto_reconnect(mut self, reconnect: bool) -> Self {
+        self.auto_reconnect = reconnect;
+        self
+    }
+
+    pub fn set_auto_reconnect(&mut self, reconnect: bool) {
+        self.auto_reconnect = reconnect;
+    }
+
+    pub fn get_auto_reconnect(&self) -> bool {
+        self.auto_reconnect
+    }
+
+    /// Pace reconnection attempts with `strategy`, implies
+    /// `auto_reconnect(true)`. The strategy is shared by all relays.
+    pub fn reconnect_strategy(mut self, strategy: Box<dyn ReconnectStrategy>) -> Self {
+        self.set_reconnect_strategy(strategy);
+        self
+    }
+
+    pub fn set_reconnect_strategy(&mut self, strategy: Box<dyn ReconnectStrategy>) {
+        self.auto_reconnect = true;
+        self.reconnect_strategy = Some(Arc::new(Mutex::new(strategy)));
+    }
+
+    /// Bounds of the default reconnection backoff: exponential from `min`
+    /// to `max`, with jitter. Implies `auto_reconnect(true)`, ignored if
+    /// `reconnect_strategy()` is set.
+    pub fn reconnect_backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.set_reconnect_backoff(min, max);
+        self
+    }
+
+    pub fn set_reconnect_backoff(&mut self, min: Duration, max: Duration) {
+        self.auto_reconnect = true;
+        self.reconnect_backoff = Some((min, max));
+    }
+
+    /// Give up reconnecting to a relay after `max` failed attempts, once all
+    /// relays are given up `try_receive()` returns `Error::ReconnectFailed`.
+    /// Unlimited (`None`) by default, ignored if `reconnect_strategy()` is set.
+    /// A limit enables `auto_reconnect()`.
+    pub fn max_reconnect_attempts(mut self, max: Option<u32>) -> Self {
+        self.set_max_reconnect_attempts(max);
+        self
+    }
+
+    pub fn set_max_reconnect_attempts(&mut self, max: Option<u32>) {
+        if max.is_some() {
+            self.auto_reconnect = true;
+        }
+        self.max_reconnect_attempts = max;
+    }
+
+    /// Send `token` as an `Authorization: Bearer` header on the websocket
+    /// handshake, for relays (mostly paid ones) gating access at the HTTP
+    /// layer. This is unrelated to NIP-42 auth, see `WsClient::authenticate()`.
+    pub fn relay_auth_token<T: Into<String>>(mut self, token: T) -> Self {
+        self.set_relay_auth_token(token);
+        self
+    }
+
+    pub fn set_relay_auth_token<T: Into<String>>(&mut self, token: T) {
+        self.options.auth_token = Some(token.into());
+    }
+
+    /// User-Agent sent on the websocket handshake, default to
+    /// `simple_nostr_client/<version>`.
+    pub fn user_agent<T: Into<String>>(mut self, user_agent: T) -> Self {
+        self.set_user_agent(user_agent);
+        self
+    }
+
+    pub fn set_user_agent<T: Into<String>>(&mut self, user_agent: T) {
+        self.options.user_agent = user_agent.into();
+    }
+
+    /// Add a header (e.g. a cookie) to the websocket handshake, a header set
+    /// twice keeps the last value.
+    pub fn header<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Self {
+        self.set_header(name, value);
+        self
+    }
+
+    pub fn set_header<N: Into<String>, V: Into<String>>(&mut self, name: N, value: V) {
+        self.options.headers.push((name.into(), value.into()));
+    }
+
+    /// Disable Nagle's algorithm on the relay socket, lowering publish
+    /// latency. Enabled by default.
+    pub fn tcp_nodelay(mut self, nodelay: bool) -> Self {
+        self.set_tcp_nodelay(nodelay);
+        self
+    }
+
+    pub fn set_tcp_nodelay(&mut self, nodelay: bool) {
+        self.options.tcp_nodelay = nodelay;
+    }
+
+    pub fn get_tcp_nodelay(&self) -> bool {
+        self.options.tcp_nodelay
+    }
+
+    /// Enable TCP keepalive on the relay socket, probing after `idle` without
+    /// traffic, so the OS also detects dead connections. Disabled by default.
+    pub fn tcp_keepalive(mut self, idle: Option<Duration>) -> Self {
+        self.set_tcp_keepalive(idle);
+        self
+    }
+
+    pub fn set_tcp_keepalive(&mut self, idle: Option<Duration>) {
+        self.options.tcp_keepalive = idle;
+    }
+
+    pub fn get_tcp_keepalive(&self) -> Option<Duration> {
+        self.options.tcp_keepalive
+    }
+
+    /// Connect through a SOCKS5 proxy (e.g. Tor at 127.0.0.1:9050), the
+    /// proxy resolving the relay host. Required for `.onion` relays.
+    pub fn proxy(mut self, proxy: SocketAddr) -> Self {
+        self.set_proxy(proxy);
+        self
+    }
+
+    pub fn set_proxy(&mut self, proxy: SocketAddr) {
+        self.options.proxy = Some(proxy);
+    }
+
+    pub fn get_proxy(&self) -> Option<SocketAddr> {
+        self.options.proxy
+    }
+
+    /// Skip TLS certificate verification for `relay`, e.g. a local relay
+    /// with a self-signed certificate. Other relays are still verified.
+    pub fn accept_invalid_certs<T: Into<String>>(mut self, relay: T) -> Self {
+        self.set_accept_invalid_certs(relay);
+        self
+    }
+
+    pub fn set_accept_invalid_certs<T: Into<String>>(&mut self, relay: T) {
+        // stored the way connect() spells the relay urls, an invalid url
+        // matches no relay anyway
+        let relay = relay.into();
+        let relay = normalize_relay_url(&relay).unwrap_or(relay);
+        self.options.insecure_tls.insert(relay);
+    }
+
+    pub fn get_accept_invalid_certs(&self) -> &HashSet<String> {
+        &self.options.insecure_tls
+    }
+
+    /// Delay between pings sent to the relays, 5s by default.
+    pub fn ping_interval(mut self, interval: Duration) -> Self {
+        self.set_ping_interval(interval);
+        self
+    }
+
+    pub fn set_ping_interval(&mut self, interval: Duration) {
+        self.options.ping_interval = interval;
+    }
+
+    pub fn get_ping_interval(&self) -> Duration {
+        self.options.ping_interval
+    }
+
+    /// Consider a relay disconnected when no pong was received for
+    /// `timeout`, 15s by default. Must be greater than `ping_interval()`.
+    pub fn pong_timeout(mut self, timeout: Duration) -> Self {
+        self.set_pong_timeout(timeout);
+        self
+    }
+
+    pub fn set_pong_timeout(&mut self, timeout: Duration) {
+        self.options.pong_timeout = timeout;
+    }
+
+    pub fn get_pong_timeout(&self) -> Duration {
+        self.options.pong_timeout
+    }
+
+    /// Write every received event to `store`, also looked up by
+    /// `WsClient::fetch_event()` before querying the relay.
+    #[cfg(feature = "store")]
+    pub fn store(mut self, store: Arc<dyn EventStore>) -> Self {
+        self.set_store(store);
+        self
+    }
+
+    #[cfg(feature = "store")]
+    pub fn set_store(&mut self, store: Arc<dyn EventStore>) {
+        self.store = Some(store);
+    }
+
+    /// Connect to every relay, failing only if none of them can be reached.
+    /// Without keys the client is read-only: publishing and encryption
+    /// return `Error::KeysMissing`.
+    pub fn connect(self) -> Result<WsClient, Error> {
+        if self.relays.is_empty() {
+            return Err(Error::ArgMissing);
+        }
+        if self.options.pong_timeout <= self.options.ping_interval {
+            return Err(Error::InvalidPongTimeout);
+        }
+        let mut relays = Vec::with_capacity(self.relays.len());
+        for url in &self.relays {
+            let url = normalize_relay_url(url)?;
+            if !relays.contains(&url) {
+                relays.push(url);
+            }
+        }
+        // at least one slot, a rendezvous channel would block on every message
+        let channel_capacity = self.get_channel_capacity().max(1);
+        let (ws_sender, receiver) = mpsc::sync_channel(channel_capacity);
+        let mut client = WsClient {
+            connections: Vec::new(),
+            receiver,
+            ws_sender,
+            channel_capacity,
+            label: self.label,
+            keys: self.keys,
+            hooks: self.hooks,
+            bandwidth_limit: self.bandwidth_limit,
+            cache_auth: self.cache_auth,
+            auto_auth: self.auto_auth,
+            auth_challenge: HashMap::new(),
+            last_auth: HashMap::new(),
+            last_auth_relay: None,
+            seen_kinds: HashSet::new(),
+            pending: VecDeque::new(),
+            routed: HashMap::new(),
+            raw: None,
+            handled: false,
+            batched: VecDeque::new(),
+            eose: HashMap::new(),
+            clock_offsets: VecDeque::new(),
+            compensate_clock_skew: self.compensate_clock_skew,
+            replay: VecDeque::with_capacity(self.replay_buffer),
+            replay_capacity: self.replay_buffer,
+            latency: HashMap::new(),
+            strict_addressable: self.strict_addressable,
+            batch_size: self.batch_size,
+            #[cfg(feature = "store")]
+            store: self.store,
+            subscriptions: HashMap::new(),
+            in_flight: HashMap::new(),
+            processing: (self.measure_processing || self.processing_budget.is_some())
+                .then(|| ProcessingTime::new(self.processing_budget)),
+            strict_subscriptions: self.strict_subscriptions,
+            paused: Arc::new(AtomicBool::new(false)),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            eose_hooks: HashMap::new(),
+            closed: HashSet::new(),
+            finished: VecDeque::new(),
+            accept_late_events: self.accept_late_events,
+            notices: VecDeque::new(),
+            reconnect: self.auto_reconnect.then(|| {
+                self.reconnect_strategy.unwrap_or_else(|| {
+                    let (min, max) = self
+                        .reconnect_backoff
+                        .unwrap_or((RECONNECT_MIN, RECONNECT_MAX));
+                    let mut backoff = ExponentialBackoff::new(min, max).jitter(true);
+                    if let Some(attempts) = self.max_reconnect_attempts {
+                        backoff = backoff.max_attempts(attempts as usize);
+                    }
+                    let strategy: Box<dyn ReconnectStrategy> = Box::new(backoff);
+                    Arc::new(Mutex::new(strategy))
+                })
+            }),
+            options: self.options,
+            verify_events: !self.skip_verification,
+            states: Arc::new(Mutex::new(Vec::new())),
+            dedup: (self.dedup > 0).then(|| SeenEvents::new(self.dedup)),
+            stats: Arc::new(Stats::default()),
+            publish_mode: self.publish_mode,
+            next_relay: 0,
+            drain_error: None,
+            received: None,
+        };
+        let mut error = None;
+        for url in relays {
+            match open(&url, &client.relay_label(&url), &client.options) {
+                Ok(ws) => client.add_connection(url, ws),
+                Err(e) => {
+                    log::error!(
+                        "[{}] connect(): fail to connect: {:?}",
+                        client.relay_label(&url),
+                        e
+                    );
+                    error = Some(e);
+                }
+            }
+        }
+        match error {
+            Some(e) if client.connections.is_empty() => Err(e),
+            _ => Ok(client),
+        }
+    }
+}
+
+impl WsClient {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> WsClientBuilder {
+        WsClientBuilder::default()
+    }
+
+    pub fn pubkey(&self) -> Result<PublicKey, Error> {
+        Ok(self.get_keys()?.public_key)
+    }
+
+    /// Our public key in its NIP-19 `npub1...` form.
+    pub fn pubkey_bech32(&self) -> Result<String, Error> {
+        self.pubkey()?.to_bech32().map_err(|_| Error::InvalidBech32)
+    }
+
+    // `label url` so the logs of each relay can be told apart
+    fn relay_label(&self, url: &str) -> String {
+        match &self.label {
+            Some(label) => format!("{label} {url}"),
+            None => url.to_string(),
+        }
+    }
+
+    // spawn the listener of the connection `client` to `url`
+    fn add_connection(&mut self, url: String, client: Socket) {
+        let (sender, receiver) = mpsc::sync_channel(self.channel_capacity);
+        let (priority_sender, priority) = mpsc::sync_channel(self.channel_capacity);
+        let stopped = Arc::new(AtomicBool::new(false));
+        let config = ListenConfig {
+            relay: url.clone(),
+            label: self.relay_label(&url),
+            hooks: self.hooks.clone(),
+            bandwidth_limit: self.bandwidth_limit,
+            batch_size: self.batch_size,
+            paused: self.paused.clone(),
+            options: self.options.clone(),
+            reconnect: self.reconnect.clone(),
+            states: self.states.clone(),
+            stats: self.stats.clone(),
+            stopped: stopped.clone(),
+        };
+        let ws_sender = self.ws_sender.clone();
+        let handle = std::thread::spawn(|| listen(client, ws_sender, receiver, priority, config));
+        self.connections.push(Connection {
+            relay: url,
+            sender,
+            priority_sender,
+            stopped,
+            handle: Some(handle),
+            connected: true,
+            last_pong: None,
+            latency: None,
+        });
+    }
+
+    pub fn encrypt<T>(&mut self, receiver: &PublicKey, content: T) -> Result<String, Error>
+    where
+        T: AsRef<[u8]>,
+    {
+        nip04::encrypt(self.get_keys()?.secret_key(), receiver, content)
+            .map_err(|_| Error::Nip04Encrypt)
+    }
+
+    pub fn encrypt_nip44<T>(&mut self, receiver: &PublicKey, content: T) -> Result<String, Error>
+    where
+        T: AsRef<[u8]>,
+    {
+        nip44::encrypt(
+            self.get_keys()?.secret_key(),
+            receiver,
+            content,
+            nip44::Version::V2,
+        )
+        .map_err(|_| Error::Nip44Encrypt)
+    }
+
+    /// Decrypt a direct message, NIP-04 or NIP-44 depending on its payload.
+    pub fn decrypt_dm(&mut self, mut event: Event) -> Result<Event, Error> {
+        event.content = try_decrypt_dm(self.get_keys()?, &event)?;
+        Ok(event)
+    }
+
+    /// Send `content` to `receiver` as a NIP-17 private message: the rumor is
+    /// sealed and gift wrapped (NIP-59) with ephemeral keys, hiding both the
+    /// sender and the timing from the relays.
+    pub fn send_private_message<T: Into<String>>(
+        &mut self,
+        content: T,
+        receiver: &PublicKey,
+    ) -> Result<EventId, Error> {
+        self.is_connected()?;
+        let keys = self.get_keys()?;
+        let rumor = EventBuilder::new(
+            Kind::PrivateDirectMessage,
+            content.into(),
+            vec![Tag::public_key(*receiver)],
+        )
+        .to_unsigned_event(keys.public_key());
+        let gift_wrap =
+            EventBuilder::gift_wrap(keys, receiver, rumor, None).map_err(|_| Error::GiftWrap)?;
+        self.send_event(gift_wrap)
+    }
+
+    /// Open a gift wrap (kind 1059) sent to us, returning the inner rumor.
+    /// The ones received by `try_receive()` are already opened.
+    pub fn unwrap_private_message(&self, gift_wrap: &Event) -> Result<UnsignedEvent, Error> {
+        let unwrapped = UnwrappedGift::from_gift_wrap(self.get_keys()?, gift_wrap)
+            .map_err(|_| Error::Unwrap)?;
+        if unwrapped.sender != unwrapped.rumor.pubkey {
+            return Err(Error::SenderMismatch);
+        }
+        Ok(unwrapped.rumor)
+    }
+
+    // the rumor, under the id and signature of the gift wrap
+    fn open_gift_wrap(&self, gift_wrap: Event) -> Result<Event, Error> {
+        let rumor = self.unwrap_private_message(&gift_wrap)?;
+        Ok(Event::new(
+            gift_wrap.id,
+            rumor.pubkey,
+            rumor.created_at,
+            rumor.kind,
+            rumor.tags,
+            rumor.content,
+            gift_wrap.sig,
+        ))
+    }
+
+    pub fn decrypt(&mut self, event_pubkey: &PublicKey, content: String) -> Result<String, Error> {
+        if !is_nip04_payload(&content) {
+            return Err(Error::NotNip04);
+        }
+        nip04::decrypt(self.get_keys()?.secret_key(), event_pubkey, content)
+            .map_err(|_| Error::Nip04Decrypt)
+    }
+
+    pub fn decrypt_nip44(
+        &mut self,
+        event_pubkey: &PublicKey,
+        content: String,
+    ) -> Result<String, Error> {
+        if is_nip04_payload(&content) {
+            return Err(Error::NotNip44);
+        }
+        nip44::decrypt(self.get_keys()?.secret_key(), event_pubkey, content)
+            .map_err(|_| Error::Nip44Decrypt)
+    }
+
+    /// Subscribe to the direct messages sent to us, pass the `created_at` of
+    /// the last one received as `since` to not download them again.
+    pub fn subscribe_dm(
+        &mut self,
+        since: Option<Timestamp>,
+        limit: Option<usize>,
+    ) -> Result<SubscriptionId, Error> {
+        self.is_connected()?;
+        let mut filter = Filter::new()
+            .kind(Kind::EncryptedDirectMessage)
+            .pubkey(self.get_keys()?.public_key());
+        if let Some(since) = since {
+            filter = filter.since(since);
+        }
+        if let Some(limit) = limit {
+            filter = filter.limit(limit);
+        }
+        self.subscribe(vec![filter])
+    }
+
+    /// Subscribe to the gift wraps sent to us, `try_receive()` returns their
+    /// rumor.
+    /// Their `created_at` is randomized up to two days in the past, keep a
+    /// margin when passing `since`.
+    pub fn subscribe_private_messages(
+        &mut self,
+        since: Option<Timestamp>,
+    ) -> Result<SubscriptionId, Error> {
+        self.is_connected()?;
+        let mut filter = Filter::new()
+            .kind(Kind::GiftWrap)
+            .pubkey(self.get_keys()?.public_key());
+        if let Some(since) = since {
+            filter = filter.since(since);
+        }
+        self.subscribe(vec![filter])
+    }
+
+    pub fn subscribe_pool(&mut self, back: u64) -> Result<SubscriptionId, Error> {
+        self.is_connected()?;
+        let since = Timestamp::now() - Timestamp::from_secs(back);
+        let filter = Filter::new().kind(Kind::Custom(2022)).since(since);
+        self.subscribe(vec![filter])
+    }
+
+    /// Subscribe to `filters` restricted to events created between `since`
+    /// and `until` (both inclusive).
+    pub fn subscribe_range(
+        &mut self,
+        filters: Vec<Filter>,
+        since: Timestamp,
+        until: Timestamp,
+    ) -> Result<SubscriptionId, Error> {
+        self.is_connected()?;
+        if since > until {
+            return Err(Error::InvalidTimeRange);
+        }
+        let filters = filters
+            .into_iter()
+            .map(|f| f.since(since).until(until))
+            .collect();
+        self.subscribe(filters)
+    }
+
+    /// Subscribe to `filters` under a new subscription id.
+    pub fn subscribe(&mut self, filters: Vec<Filter>) -> Result<SubscriptionId, Error> {
+        self.is_connected()?;
+        self.req_with_id(SubscriptionId::generate(), filters)
+    }
+
+    fn req_with_id(
+        &mut self,
+        id: SubscriptionId,
+        filters: Vec<Filter>,
+    ) -> Result<SubscriptionId, Error> {
+        self.send_priority(ClientMessage::req(id.clone(), filters.clone()))?;
+        self.latency.insert(id.clone(), SubscriptionLatency::new());
+        self.closed.remove(&id);
+        self.subscriptions.insert(id.clone(), filters);
+        Ok(id)
+    }
+
+    fn close(&mut self, id: SubscriptionId) -> Result<(), Error> {
+        self.latency.remove(&id);
+        self.eose.remove(&id);
+        self.eose_hooks.remove(&id);
+        self.subscriptions.remove(&id);
+        self.send_priority(ClientMessage::close(id))
+    }
+
+    /// Send a CLOSE for subscription `id` so the relays stop sending its
+    /// events.
+    pub fn close_subscription(&mut self, id: SubscriptionId) -> Result<(), Error> {
+        self.is_connected()?;
+        if !self.subscriptions.contains_key(&id) {
+            return Err(Error::SubscriptionNotFound(id));
+        }
+        self.close(id)
+    }
+
+    /// Open subscriptions and their filters, both serializable with serde so
+    /// they can be persisted and given back to `import_subscriptions()`.
+    pub fn export_subscriptions(&self) -> Vec<(SubscriptionId, Vec<Filter>)> {
+        self.subscriptions
+            .iter()
+            .map(|(id, filters)| (id.clone(), filters.clone()))
+            .collect()
+    }
+
+    /// Re-open subscriptions previously exported, keeping their ids.
+    pub fn import_subscriptions(
+        &mut self,
+        subscriptions: Vec<(SubscriptionId, Vec<Filter>)>,
+    ) -> Result<(), Error> {
+        self.is_connected()?;
+        for (id, filters) in subscriptions {
+            self.req_with_id(id, filters)?;
+        }
+        Ok(())
+    }
+
+    /// Move to the relay at `url`: connect to it, replay the open
+    /// subscriptions there, then close the current connections once the new
+    /// relay sent their stored events (or after 10s). The current relays keep
+    /// delivering meanwhile so no event is missed. If the new relay can't be
+    /// reached the current connections are kept.
+    pub fn switch_relay(&mut self, url: String) -> Result<(), Error> {
+        let url = normalize_relay_url(&url)?;
+        let client = open(&url, &self.relay_label(&url), &self.options)?;
+
+        // the current listeners keep feeding the same channel until the end
+        let old = std::mem::take(&mut self.connections);
+        self.add_connection(url, client);
+        let subscriptions = self.export_subscriptions();
+        let replayed = subscriptions.iter().try_for_each(|(id, filters)| {
+            self.send_priority(ClientMessage::req(id.clone(), filters.clone()))
+        });
+        if let Err(e) = replayed {
+            for connection in self.connections.drain(..) {
+                connection.stop();
+            }
+            self.connections = old;
+            return Err(e);
+        }
+        self.auth_challenge.clear();
+        self.eose.clear();
+        self.closed.clear();
+        for (id, _) in &subscriptions {
+            self.latency.insert(id.clone(), SubscriptionLatency::new());
+        }
+
+        let deadline = Instant::now() + SWITCH_TIMEOUT;
+        for (id, _) in &subscriptions {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if let Err(e) = self.wait_for_eose(id, timeout) {
+                log::warn!(
+                    "[{}] switch_relay(): no EOSE for {}: {:?}",
+                    self.name(),
+                    id,
+                    e
+                );
+            }
+        }
+        for connection in old {
+            connection.stop();
+        }
+        Ok(())
+    }
+
+    /// Connect to one more relay and replay the open subscriptions there. Does
+    /// nothing if already connected to it.
+    pub fn add_relay(&mut self, url: String) -> Result<(), Error> {
+        let url = normalize_relay_url(&url)?;
+        if self
+            .connections
+            .iter()
+            .any(|c| c.connected && c.relay == url)
+        {
+            return Ok(());
+        }
+        let client = open(&url, &self.relay_label(&url), &self.options)?;
+        self.connections.retain(|c| c.relay != url);
+        self.add_connection(url.clone(), client);
+        for (id, filters) in self.export_subscriptions() {
+            self.send_priority_to(&url, ClientMessage::req(id, filters))?;
+        }
+        Ok(())
+    }
+
+    /// Close the connection to the relay at `url`, the other relays are kept.
+    pub fn remove_relay(&mut self, url: &str) -> Result<(), Error> {
+        let url = normalize_relay_url(url)?;
+        let url = url.as_str();
+        let index = self
+            .connections
+            .iter()
+            .position(|c| c.relay == url)
+            .ok_or_else(|| Error::RelayNotFound(url.to_string()))?;
+        let connection = self.connections.remove(index);
+        connection.stop();
+        self.auth_challenge.remove(url);
+        self.last_auth.remove(url);
+        Ok(())
+    }
+
+    /// Send an encrypted direct message to `receiver`, returning its event id
+    /// to match the relay OK.
+    pub fn send_dm<T: Into<String>>(
+        &mut self,
+        content: T,
+        receiver: &PublicKey,
+        encryption: Encryption,
+    ) -> Result<EventId, Error> {
+        let content = match encryption {
+            Encryption::Nip04 => self.encrypt(receiver, content.into())?,
+            Encryption::Nip44 => self.encrypt_nip44(receiver, content.into())?,
+        };
+        let dm = EventBuilder::new(
+            Kind::EncryptedDirectMessage,
+            content,
+            vec![Tag::public_key(*receiver)],
+        );
+        self.is_connected()?;
+        let dm = self.sign(dm, self.get_keys()?)?;
+        self.send_event(dm)
+    }
+
+    // send `msg` to every connected relay, failing only if none accepted it
+    fn send_raw(&mut self, msg: Message) -> Result<(), Error> {
+        self.is_connected()?;
+        let mut result = Err(Error::Send);
+        for connection in self.connections.iter().filter(|c| c.connected) {
+            match try_queue(&connection.sender, SendMsg::Msg(msg.clone())) {
+                Ok(()) => result = Ok(()),
+                Err(e) => self.skip_relay(&connection.relay, e, &mut result),
+            }
+        }
+        result
+    }
+
+    // a relay whose queue is full misses the message, the others still get it
+    fn skip_relay(&self, relay: &str, e: Error, result: &mut Result<(), Error>) {
+        if let Error::SendQueueFull = e {
+            log::warn!(
+                "[{}] send queue of {} full, message dropped",
+                self.name(),
+                relay
+            );
+            if result.is_err() {
+                *result = Err(e);
+            }
+        }
+    }
+
+    // send `msg` to the next connected relay, moving on to the following one
+    // if its listener is gone or its queue full
+    fn send_round_robin(&mut self, msg: Message) -> Result<(), Error> {
+        self.is_connected()?;
+        let connected: Vec<usize> = (0..self.connections.len())
+            .filter(|i| self.connections[*i].connected)
+            .collect();
+        let mut result = Err(Error::Send);
+        for _ in 0..connected.len() {
+            let index = connected[self.next_relay % connected.len()];
+            self.next_relay = self.next_relay.wrapping_add(1);
+            let connection = &self.connections[index];
+            match try_queue(&connection.sender, SendMsg::Msg(msg.clone())) {
+                Ok(()) => return Ok(()),
+                Err(e) => self.skip_relay(&connection.relay, e, &mut result),
+            }
+        }
+        result
+    }
+
+    /// Send any `ClientMessage` (COUNT, NEG-OPEN, ...) to every connected
+    /// relay through the regular path.
+    pub fn send_client_message(&mut self, msg: ClientMessage) -> Result<(), Error> {
+        self.send_raw(msg.as_json())
+    }
+
+    /// Send `msg` to every connected relay ahead of any message queued with
+    /// the regular path, used for AUTH, REQ and CLOSE so they don't wait
+    /// behind a publish batch.
+    pub fn send_priority(&mut self, msg: ClientMessage) -> Result<(), Error> {
+        self.is_connected()?;
+        let msg = msg.as_json();
+        let mut result = Err(Error::Send);
+        for connection in self.connections.iter().filter(|c| c.connected) {
+            match try_queue(&connection.priority_sender, msg.clone()) {
+                Ok(()) => result = Ok(()),
+                Err(e) => self.skip_relay(&connection.relay, e, &mut result),
+            }
+        }
+        result
+    }
+
+    fn send_priority_to(&mut self, relay: &str, msg: ClientMessage) -> Result<(), Error> {
+        let connection = self
+            .connections
+            .iter()
+            .find(|c| c.connected && c.relay == relay)
+            .ok_or(Error::NotConnected)?;
+        try_queue(&connection.priority_sender, msg.as_json())
+    }
+
+    fn try_receive_raw(&mut self) -> Result<Option<RecvMsg>, Error> {
+        self.is_connected()?;
+        let msg = loop {
+            let next = match self.received.take() {
+                Some(m) => Ok(m),
+                None => self.receiver.try_recv(),
+            };
+            match next {
+                Ok(RecvMsg::Pong(relay, at, rtt)) => {
+                    for connection in self.connections.iter_mut().filter(|c| c.relay == relay) {
+                        connection.last_pong = Some(at);
+                        connection.latency = Some(rtt);
+                    }
+                }
+                Ok(RecvMsg::Reconnected(relay)) => self.handle_reconnected(&relay),
+                Ok(m) => break Ok(Some(m)),
+                Err(mpsc::TryRecvError::Empty) => break Ok(None),
+                Err(mpsc::TryRecvError::Disconnected) => break Err(Error::Receive),
+            }
+        };
+        if let Ok(Some(RecvMsg::Close(relay))) = &msg {
+            for connection in self.connections.iter_mut().filter(|c| c.relay == *relay) {
+                connection.connected = false;
+            }
+        }
+        msg
+    }
+
+    // the relay sends the stored events again on the replayed REQs, they
+    // must not be taken for live ones
+    fn handle_reconnected(&mut self, relay: &str) {
+        log::debug!("[{}] reconnected to {}", self.name(), relay);
+        for stored in self.eose.values_mut() {
+            stored.remove(relay);
+        }
+        for id in self.subscriptions.keys() {
+            self.latency.insert(id.clone(), SubscriptionLatency::new());
+        }
+    }
+
+    // block until the listeners deliver something, kept for try_receive_raw()
+    fn wait_raw(&mut self) -> Result<(), Error> {
+        if self.received.is_none() {
+            self.received = Some(self.receiver.recv().map_err(|_| Error::Receive)?);
+        }
+        Ok(())
+    }
+
+    // like wait_raw(), but no longer than `timeout`
+    fn wait_raw_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
+        if self.received.is_none() {
+            match self.receiver.recv_timeout(timeout) {
+                Ok(m) => self.received = Some(m),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Err(Error::Receive),
+            }
+        }
+        Ok(())
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    // messages put aside by blocking helpers are delivered first, then the
+    // ones kept by try_receive_for()
+    fn next_relay_message(&mut self) -> Result<Option<(String, RelayMessage)>, Error> {
+        if self.pending.is_empty() {
+            let key = self.routed.keys().next().cloned();
+            if let Some(deferred) = key.and_then(|key| self.pop_routed(&key)) {
+                return Ok(Some(self.restore(deferred)));
+            }
+        }
+        self.next_unrouted()
+    }
+
+    fn next_unrouted(&mut self) -> Result<Option<(String, RelayMessage)>, Error> {
+        if let Some(deferred) = self.pending.pop_front() {
+            return Ok(Some(self.restore(deferred)));
+        }
+        if let Some((relay, t)) = self.batched.pop_front() {
+            return self.parse_keeping_raw(relay, t);
+        }
+        match self.try_receive_raw()? {
+            Some(m) => match m {
+                RecvMsg::Close(relay) => {
+                    if self.is_connected().is_ok() {
+                        log::warn!("[{}] connection to {} closed", self.name(), relay);
+                        Ok(None)
+                    } else if self.reconnect.is_some() {
+                        // listeners only close once reconnection gave up
+                        Err(Error::ReconnectFailed)
+                    } else {
+                        Err(Error::ConnectionClosed)
+                    }
+                }
+                // only wakes up a blocking receive
+                RecvMsg::Cancel => Ok(None),
+                // handled by try_receive_raw()
+                RecvMsg::Pong(..) | RecvMsg::Reconnected(_) => Ok(None),
+                RecvMsg::Msg(relay, t) => self.parse_keeping_raw(relay, t),
+                RecvMsg::Batch(relay, batch) => {
+                    self.batched.extend(tag_relay(relay, batch));
+                    match self.batched.pop_front() {
+                        Some((relay, t)) => self.parse_keeping_raw(relay, t),
+                        None => Ok(None),
+                    }
+                }
+            },
+            None => Ok(None),
+        }
+    }
+
+    // relay messages already taken from the channel
+    fn has_buffered(&self) -> bool {
+        !self.pending.is_empty() || !self.routed.is_empty() || !self.batched.is_empty()
+    }
+
+    fn restore(&mut self, deferred: Deferred) -> (String, RelayMessage) {
+        self.raw = deferred.raw;
+        self.handled = deferred.handled;
+        (deferred.relay, deferred.msg)
+    }
+
+    fn pop_routed(&mut self, key: &Option<SubscriptionId>) -> Option<Deferred> {
+        let queue = self.routed.get_mut(key)?;
+        let deferred = queue.pop_front();
+        if queue.is_empty() {
+            self.routed.remove(key);
+        }
+        deferred
+    }
+
+    // keep a message for another subscription than the one try_receive_for()
+    // waits on, dropping the oldest one of its queue once full
+    fn route(&mut self, (relay, msg): (String, RelayMessage)) {
+        let handled = std::mem::take(&mut self.handled) || self.handle_control(&relay, &msg);
+        let key = subscription_of(&msg).cloned();
+        let queue = self.routed.entry(key.clone()).or_default();
+        let full = queue.len() >= self.channel_capacity;
+        if full {
+            queue.pop_front();
+        }
+        queue.push_back(Deferred {
+            relay,
+            msg,
+            raw: self.raw.take(),
+            handled,
+        });
+        if full {
+            log::warn!(
+                "[{}] try_receive_for(): queue of {:?} full, dropping oldest message",
+                self.name(),
+                key
+            );
+        }
+    }
+
+    fn parse_keeping_raw(
+        &mut self,
+        relay: String,
+        text: Message,
+    ) -> Result<Option<(String, RelayMessage)>, Error> {
+        let msg = parse_relay_message(&text)?;
+        self.raw = Some(text);
+        self.handled = false;
+        Ok(Some((relay, msg)))
+    }
+
+    // keep a message received by a blocking helper for `try_receive()`, once
+    // AUTH, OK and NOTICE are handled: an auth challenge must be answered
+    // while the helper waits, not after
+    fn set_aside(&mut self, deferred: &mut Vec<Deferred>, (relay, msg): (String, RelayMessage)) {
+        let handled = std::mem::take(&mut self.handled) || self.handle_control(&relay, &msg);
+        deferred.push(Deferred {
+            relay,
+            msg,
+            raw: self.raw.take(),
+            handled,
+        });
+    }
+
+    fn handle_event(&mut self, event: Event) -> Result<Event, Error> {
+        let Some(processing) = &self.processing else {
+            return self.process_event(event);
+        };
+        let budget = processing.budget;
+        let (kind, id) = (event.kind, event.id);
+        let start = Instant::now();
+        let result = self.process_event(event);
+        let elapsed = start.elapsed();
+        if budget.is_some_and(|b| elapsed > b) {
+            log::warn!(
+                "[{}] processing event {} of kind {} took {:?}",
+                self.name(),
+                id,
+                kind,
+                elapsed
+            );
+        }
+        if let Some(processing) = &mut self.processing {
+            processing.record(elapsed);
+        }
+        result
+    }
+
+    fn process_event(&mut self, event: Event) -> Result<Event, Error> {
+        // before storing or decrypting anything a relay could have forged
+        if self.verify_events && event.verify().is_err() {
+            log::warn!("[{}] invalid event {} dropped", self.name(), event.id);
+            return Err(Error::InvalidEvent(event.id));
+        }
+        self.seen_kinds.insert(event.kind);
+        #[cfg(feature = "store")]
+        if let Some(store) = &self.store {
+            store.put(&event);
+        }
+        // keep events as received so they can still be verified once exported
+        if self.replay_capacity > 0 {
+            if self.replay.len() == self.replay_capacity {
+                self.replay.pop_front();
+            }
+            self.replay.push_back(event.clone());
+        }
+        // a read-only client has nothing to decrypt with
+        #[allow(deprecated)]
+        if event.kind() == Kind::EncryptedDirectMessage && self.keys.is_some() {
+            self.decrypt_dm(event)
+        } else if event.kind() == Kind::GiftWrap && self.keys.is_some() {
+            self.open_gift_wrap(event)
+        } else {
+            Ok(event)
+        }
+    }
+
+    /// The last events received, oldest first, see
+    /// `WsClientBuilder::replay_buffer()`.
+    pub fn recent_events(&self) -> Vec<Event> {
+        self.replay
+            .iter()
+            .cloned()
+            .map(|mut event| {
+                if let Some(Ok(content)) = self.keys.as_ref().map(|k| try_decrypt_dm(k, &event)) {
+                    event.content = content;
+                }
+                event
+            })
+            .collect()
+    }
+
+    /// Write the events of the replay buffer to `writer` as newline-delimited
+    /// JSON, oldest first. Events are written as received (direct messages
+    /// still encrypted) so they can be verified and imported again.
+    pub fn export_events<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        for event in &self.replay {
+            writeln!(writer, "{}", event.as_json())?;
+        }
+        writer.flush()
+    }
+
+    pub fn try_receive(&mut self) -> Result<Option<Event>, Error> {
+        self.try_receive_with_source()
+            .map(|received| received.map(|(event, _)| event))
+    }
+
+    /// Like `try_receive()` but also return the url of the relay that sent
+    /// the event.
+    pub fn try_receive_with_source(&mut self) -> Result<Option<(Event, String)>, Error> {
+        self.receive()
+    }
+
+    /// Like `try_receive()` but only return the events of subscription `id`.
+    /// Messages for other subscriptions are kept, in order for each of them,
+    /// for the next `try_receive()` or `try_receive_for()`: up to
+    /// `channel_capacity` per subscription, the oldest are dropped beyond.
+    pub fn try_receive_for(&mut self, id: &SubscriptionId) -> Result<Option<Event>, Error> {
+        let key = Some(id.clone());
+        loop {
+            // what an earlier call kept for `id` first
+            let msg = match self.pop_routed(&key) {
+                Some(deferred) => Ok(Some(self.restore(deferred))),
+                None => self.next_unrouted(),
+            };
+            match msg {
+                Ok(Some(msg)) if subscription_of(&msg.1) == Some(id) => {
+                    // let receive() handle it as usual
+                    let (relay, msg) = msg;
+                    self.pending.push_front(Deferred {
+                        relay,
+                        msg,
+                        raw: self.raw.take(),
+                        handled: std::mem::take(&mut self.handled),
+                    });
+                    match self.receive() {
+                        Ok(Some((event, _))) => break Ok(Some(event)),
+                        Ok(None) => {}
+                        Err(e) => break Err(e),
+                    }
+                }
+                Ok(Some(msg)) => self.route(msg),
+                Ok(None) => break Ok(None),
+                Err(e) => break Err(e),
+            }
+        }
+    }
+
+    /// Like `try_receive()` but also return the relay message carrying the
+    /// event, byte for byte as sent by the relay (DMs still encrypted), e.g.
+    /// for archival or re-broadcast.
+    pub fn try_receive_raw_json(&mut self) -> Result<Option<(Event, String)>, Error> {
+        match self.try_receive()? {
+            Some(event) => {
+                let raw = self.raw.take().ok_or(Error::RelayMessage)?;
+                Ok(Some((event, raw)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Iterate over the events as they arrive, blocking until the next one.
+    /// Ends once no relay is connected anymore or on a `Canceller`, errors
+    /// (e.g. a DM that can't be decrypted) are yielded as `Err` items.
+    pub fn events(&mut self) -> impl Iterator<Item = Result<Event, Error>> + '_ {
+        self.cancelled.store(false, Ordering::Relaxed);
+        std::iter::from_fn(move || loop {
+            if self.is_cancelled() {
+                return None;
+            }
+            match self.receive() {
+                Ok(Some((event, _))) => return Some(Ok(event)),
+                Ok(None) if !self.has_buffered() => {
+                    if self.wait_raw().is_err() {
+                        return None;
+                    }
+                }
+                Ok(None) => {}
+                Err(
+                    Error::ConnectionClosed
+                    | Error::ReconnectFailed
+                    | Error::NotConnected
+                    | Error::Receive,
+                ) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        })
+    }
+
+    /// Return all the events received so far, DMs decrypted. DMs that can't
+    /// be decrypted are logged and skipped. Other errors (e.g.
+    /// `Error::SubscriptionClosed`) don't stop the drain: the first one is
+    /// returned once the events are, or right away if there was none. Stops
+    /// at a closed connection, returning the events received before it.
+    /// Takes at most `channel_capacity` deliveries of the listeners, so a
+    /// busy relay can't keep it draining.
+    pub fn try_receive_all(&mut self) -> Result<Vec<Event>, Error> {
+        if let Some(e) = self.drain_error.take() {
+            return Err(e);
+        }
+        // take what the listeners delivered, up to a full channel
+        for _ in 0..self.channel_capacity {
+            let Some(msg) = self.try_receive_raw()? else {
+                break;
+            };
+            match msg {
+                RecvMsg::Msg(relay, m) => self.batched.push_back((relay, m)),
+                RecvMsg::Batch(relay, batch) => self.batched.extend(tag_relay(relay, batch)),
+                RecvMsg::Close(relay) => {
+                    log::warn!("[{}] connection to {} closed", self.name(), relay);
+                    break;
+                }
+                RecvMsg::Pong(..) | RecvMsg::Reconnected(_) | RecvMsg::Cancel => {}
+            }
+        }
+        let mut events = Vec::new();
+        let mut error = None;
+        while self.has_buffered() {
+            match self.receive() {
+                Ok(Some((event, _))) => events.push(event),
+                Ok(None) => {}
+                Err(
+                    e @ (Error::Nip04Decrypt
+                    | Error::NotNip04
+                    | Error::Nip44Decrypt
+                    | Error::NotNip44
+                    | Error::Unwrap
+                    | Error::SenderMismatch),
+                ) => log::warn!("[{}] try_receive_all(): {:?}", self.name(), e),
+                Err(e) => {
+                    log::debug!("[{}] try_receive_all(): {:?}", self.name(), e);
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        match error {
+            Some(e) if events.is_empty() => Err(e),
+            error => {
+                self.drain_error = error;
+                Ok(events)
+            }
+        }
+    }
+
+    /// Wait up to `timeout` for an event, can be interrupted from another
+    /// thread with a `Canceller`.
+    pub fn receive_timeout(&mut self, timeout: Duration) -> Result<Option<Event>, Error> {
+        self.cancelled.store(false, Ordering::Relaxed);
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+            if let Some((event, _)) = self.receive()? {
+                return Ok(Some(event));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            if !self.has_buffered() {
+                self.wait_raw_timeout(deadline - now)?;
+            }
+        }
+    }
+
+    /// Like `fetch_relay_info()`, through the proxy and with the TLS settings
+    /// of the client. NIP-11 has no preferred url for a relay, an app that
+    /// finds one in an extension field can move with `switch_relay()`.
+    pub fn relay_info(&self, relay: &str) -> Result<RelayInformationDocument, Error> {
+        relay_info(relay, &self.options)
+    }
+
+    /// Messages, pings and reconnections counted over all relays.
+    pub fn stats(&self) -> ClientStats {
+        self.stats.snapshot()
+    }
+
+    /// Receive the `(relay, state)` transitions of the relay connections
+    /// from now on, starting with `Connected` once a relay added by
+    /// `add_relay()` is. They are connected when `connect()` returns.
+    pub fn connection_events(&self) -> Receiver<(String, ConnectionState)> {
+        let (sender, receiver) = mpsc::channel();
+        if let Ok(mut senders) = self.states.lock() {
+            senders.push(sender);
+        }
+        receiver
+    }
+
+    pub fn canceller(&self) -> Canceller {
+        Canceller {
+            cancelled: self.cancelled.clone(),
+            sender: self.ws_sender.clone(),
+        }
+    }
+
+    /// Run `hook` once every relay sent all stored events of subscription `id`.
+    /// The hook is called from `try_receive()` when processing the EOSE, or
+    /// right away if it was already received.
+    pub fn on_eose(&mut self, id: SubscriptionId, mut hook: EoseHook) {
+        if self.eose_complete(&id) {
+            hook();
+        } else {
+            self.eose_hooks.insert(id, hook);
+        }
+    }
+
+    /// Stop forwarding messages from the relay, without closing the connection
+    /// or the subscriptions. The listener holds up to 10 000 messages while
+    /// paused, dropping the oldest ones beyond that, and delivers them on
+    /// `resume()`. Messages already received are still returned by
+    /// `try_receive()`.
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Oldest NOTICE received from the relays and not yet returned, the last
+    /// 100 are kept.
+    pub fn try_notice(&mut self) -> Option<String> {
+        self.notices.pop_front()
+    }
+
+    /// Like `try_receive()` but return every relay message, after the client
+    /// handled it: events are returned decrypted, and those dropped by
+    /// `strict_subscriptions()` or received after CLOSED are not returned.
+    pub fn try_receive_message(&mut self) -> Result<Option<RelayMessage>, Error> {
+        self.receive_message().map(|msg| msg.map(|(_, msg)| msg))
+    }
+
+    fn receive(&mut self) -> Result<Option<(Event, String)>, Error> {
+        match self.receive_message()? {
+            Some((relay, RelayMessage::Event { event, .. })) => Ok(Some((*event, relay))),
+            Some((_, RelayMessage::Notice { message })) if is_filter_narrowed(&message) => {
+                Err(Error::FilterNarrowed { detail: message })
+            }
+            Some((
+                _,
+                RelayMessage::Ok {
+                    event_id,
+                    status: false,
+                    message,
+                },
+            )) if self.is_auth_event(&event_id) => Err(Error::Auth(message)),
+            Some((
+                _,
+                RelayMessage::Closed {
+                    subscription_id,
+                    message,
+                },
+            )) => Err(Error::SubscriptionClosed {
+                id: subscription_id,
+                message,
+            }),
+            _ => Ok(None),
+        }
+    }
+
+    fn receive_message(&mut self) -> Result<Option<(String, RelayMessage)>, Error> {
+        let Some((relay, rm)) = self.next_relay_message()? else {
+            return Ok(None);
+        };
+        if !std::mem::take(&mut self.handled) {
+            self.handle_control(&relay, &rm);
+        }
+        if subscription_of(&rm).is_some_and(|id| self.finished.contains(id)) {
+            log::trace!("[{}] dropping a late message of a query", self.name());
+            return Ok(None);
+        }
+        match &rm {
+            RelayMessage::Event {
+                subscription_id,
+                event,
+            } => {
+                if self.closed.contains(subscription_id) {
+                    Stats::incr(&self.stats.late_events);
+                    if !self.accept_late_events {
+                        log::debug!(
+                            "[{}] ignoring event {} received after subscription {} was closed",
+                            self.name(),
+                            event.id,
+                            subscription_id
+                        );
+                        return Ok(None);
+                    }
+                    log::warn!(
+                        "[{}] event {} received after subscription {} was closed",
+                        self.name(),
+                        event.id,
+                        subscription_id
+                    );
+                } else if self.strict_subscriptions
+                    && !self.subscriptions.contains_key(subscription_id)
+                {
+                    log::debug!(
+                        "[{}] dropping event {} from unknown subscription {}",
+                        self.name(),
+                        event.id,
+                        subscription_id
+                    );
+                    Stats::incr(&self.stats.dropped_events);
+                    return Ok(None);
+                }
+                Stats::incr(&self.stats.events_received);
+                // only recorded once handled, a forged copy must not hide
+                // the genuine event
+                if self.dedup.as_ref().is_some_and(|d| d.contains(&event.id)) {
+                    log::trace!("[{}] duplicate event {} ignored", self.name(), event.id);
+                    return Ok(None);
+                }
+                // stored events are backdated, not a clock sample
+                if self.eose_complete(subscription_id) {
+                    self.sample_clock(event);
+                }
+                if let Some(latency) = self.latency.get_mut(subscription_id) {
+                    latency.first_event();
+                }
+            }
+            RelayMessage::EndOfStoredEvents(subscription_id) => {
+                self.handle_eose(subscription_id.clone(), &relay);
+            }
+            RelayMessage::Closed {
+                subscription_id,
+                message,
+            } => self.handle_closed(subscription_id, message),
+            _ => {}
+        }
+        // decrypt and store events once the checks above passed
+        let rm = match rm {
+            RelayMessage::Event {
+                subscription_id,
+                event,
+            } => {
+                let event = self.handle_event(*event)?;
+                if let Some(dedup) = &mut self.dedup {
+                    dedup.insert(event.id);
+                }
+                RelayMessage::Event {
+                    subscription_id,
+                    event: Box::new(event),
+                }
+            }
+            rm => rm,
+        };
+        Ok(Some((relay, rm)))
+    }
+
+    // bookkeeping of AUTH, OK and NOTICE, returns false for other messages
+    fn handle_control(&mut self, relay: &str, rm: &RelayMessage) -> bool {
+        match rm {
+            RelayMessage::Notice { message } => {
+                if !is_filter_narrowed(message) {
+                    log::debug!("[{}] notice: {}", self.name(), message);
+                }
+                if self.notices.len() == MAX_NOTICES {
+                    self.notices.pop_front();
+                }
+                self.notices.push_back(message.clone());
+            }
+            RelayMessage::Ok {
+                event_id,
+                status,
+                message,
+            } => {
+                let kind = self.in_flight.remove(event_id);
+                if !status {
+                    log::warn!(
+                        "[{}] event {} of kind {} rejected: {}",
+                        self.name(),
+                        event_id,
+                        kind.map(|k| k.as_u16().to_string())
+                            .unwrap_or_else(|| "?".into()),
+                        message
+                    );
+                }
+            }
+            RelayMessage::Auth { challenge } => {
+                log::debug!("[{}] {} requested auth: {}", self.name(), relay, challenge);
+                self.auth_challenge
+                    .insert(relay.to_string(), challenge.clone());
+                if self.auto_auth {
+                    if let Err(e) = self.authenticate_relay(relay.to_string(), challenge.clone()) {
+                        log::error!(
+                            "[{}] fail to authenticate to {}: {:?}",
+                            self.name(),
+                            relay,
+                            e
+                        );
+                    }
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn handle_closed(&mut self, id: &SubscriptionId, message: &str) {
+        log::debug!(
+            "[{}] relay closed subscription {}: {}",
+            self.name(),
+            id,
+            message
+        );
+        self.latency.remove(id);
+        self.eose.remove(id);
+        self.eose_hooks.remove(id);
+        self.subscriptions.remove(id);
+        self.closed.insert(id.clone());
+    }
+
+    // the hook and the latency wait for every relay
+    fn handle_eose(&mut self, id: SubscriptionId, relay: &str) {
+        self.eose
+            .entry(id.clone())
+            .or_default()
+            .insert(relay.to_string());
+        if !self.eose_complete(&id) {
+            return;
+        }
+        if let Some(latency) = self.latency.get_mut(&id) {
+            latency.eose();
+        }
+        if let Some(mut hook) = self.eose_hooks.remove(&id) {
+            hook();
+        }
+    }
+
+    // whether every connected relay sent EOSE for `id`
+    fn eose_complete(&self, id: &SubscriptionId) -> bool {
+        let Some(stored) = self.eose.get(id) else {
+            return false;
+        };
+        self.connections
+            .iter()
+            .filter(|c| c.connected)
+            .all(|c| stored.contains(&c.relay))
+    }
+
+    /// Wait up to `timeout` for every relay to send EOSE for subscription
+    /// `id`. Other relay messages received meanwhile are kept for
+    /// `try_receive()`.
+    pub fn wait_for_eose(&mut self, id: &SubscriptionId, timeout: Duration) -> Result<(), Error> {
+        self.is_connected()?;
+        if self.eose_complete(id) {
+            return Ok(());
+        }
+        let deadline = Instant::now() + timeout;
+        let mut deferred = Vec::new();
+        let result = loop {
+            if Instant::now() >= deadline {
+                break Err(Error::EoseTimeout);
+            }
+            match self.next_relay_message() {
+                Ok(Some((relay, RelayMessage::EndOfStoredEvents(sub_id)))) if sub_id == *id => {
+                    self.handle_eose(sub_id, &relay);
+                    if self.eose_complete(id) {
+                        break Ok(());
+                    }
+                }
+                Ok(Some((
+                    _,
+                    RelayMessage::Closed {
+                        subscription_id,
+                        message,
+                    },
+                ))) if subscription_id == *id => {
+                    self.handle_closed(&subscription_id, &message);
+                    break Err(Error::SubscriptionClosed {
+                        id: subscription_id,
+                        message,
+                    });
+                }
+                Ok(Some(msg)) => self.set_aside(&mut deferred, msg),
+                Ok(None) => std::thread::sleep(POLL_INTERVAL),
+                Err(e) => break Err(e),
+            }
+        };
+        for msg in deferred.into_iter().rev() {
+            self.pending.push_front(msg);
+        }
+        result
+    }
+
+    /// Open a subscription for `filters`, collect its events until the
+    /// limit, EOSE (if `close_on_eose`) or the timeout is reached, then
+    /// close it. Each event is returned once even if several relays send
+    /// it. Other relay messages received meanwhile are kept for
+    /// `try_receive()`. With `auto_auth`, a relay closing it as
+    /// `auth-required:` is asked again once authenticated.
+    pub fn query(
+        &mut self,
+        filters: Vec<Filter>,
+        options: QueryOptions,
+    ) -> Result<Vec<Event>, Error> {
+        self.is_connected()?;
+        let id = self.subscribe(filters.clone())?;
+        let deadline = Instant::now() + options.timeout;
+        let mut events = Vec::new();
+        let mut ids = HashSet::new();
+        let mut deferred = Vec::new();
+        let mut stored = HashSet::new();
+        let mut refused = HashSet::new();
+        // relays asked again once authenticated, and the ones whose
+        // challenge is still awaited
+        let mut retried = HashSet::new();
+        let mut awaiting_auth = HashSet::new();
+        self.cancelled.store(false, Ordering::Relaxed);
+        let result = loop {
+            if self.is_cancelled() {
+                break Err(Error::Cancelled);
+            }
+            if options.limit.is_some_and(|l| events.len() >= l) || Instant::now() >= deadline {
+                break Ok(());
+            }
+            match self.next_relay_message() {
+                Ok(Some((
+                    _,
+                    RelayMessage::Event {
+                        subscription_id,
+                        event,
+                    },
+                ))) if subscription_id == id => {
+                    if ids.contains(&event.id) {
+                        continue;
+                    }
+                    match self.handle_event(*event) {
+                        Ok(event) => {
+                            ids.insert(event.id);
+                            events.push(event);
+                        }
+                        Err(e) => {
+                            log::error!("[{}] query(): fail to handle event: {:?}", self.name(), e)
+                        }
+                    }
+                }
+                Ok(Some((
+                    relay,
+                    RelayMessage::Closed {
+                        subscription_id,
+                        message,
+                    },
+                ))) if subscription_id == id
+                    && self.auto_auth
+                    && message.starts_with("auth-required:")
+                    && !retried.contains(&relay) =>
+                {
+                    retried.insert(relay.clone());
+                    if self.auth_challenge.contains_key(&relay) {
+                        // answered meanwhile, ask again
+                        let req = ClientMessage::req(id.clone(), filters.clone());
+                        if let Err(e) = self.send_priority_to(&relay, req) {
+                            log::warn!("[{}] query(): fail to resend REQ: {:?}", self.name(), e);
+                        }
+                    } else {
+                        awaiting_auth.insert(relay);
+                    }
+                }
+                Ok(Some((relay, RelayMessage::Auth { challenge })))
+                    if awaiting_auth.contains(&relay) =>
+                {
+                    awaiting_auth.remove(&relay);
+                    self.set_aside(
+                        &mut deferred,
+                        (relay.clone(), RelayMessage::Auth { challenge }),
+                    );
+                    let req = ClientMessage::req(id.clone(), filters.clone());
+                    if let Err(e) = self.send_priority_to(&relay, req) {
+                        log::warn!("[{}] query(): fail to resend REQ: {:?}", self.name(), e);
+                    }
+                }
+                Ok(Some((
+                    relay,
+                    RelayMessage::Closed {
+                        subscription_id,
+                        message,
+                    },
+                ))) if subscription_id == id => {
+                    self.handle_closed(&subscription_id, &message);
+                    refused.insert(relay);
+                    if self
+                        .connections
+                        .iter()
+                        .filter(|c| c.connected)
+                        .all(|c| refused.contains(&c.relay))
+                    {
+                        break Err(Error::SubscriptionClosed {
+                            id: subscription_id,
+                            message,
+                        });
+                    }
+                }
+                Ok(Some((relay, RelayMessage::EndOfStoredEvents(sub_id)))) if sub_id == id => {
+                    // wait for the stored events of every relay
+                    stored.insert(relay);
+                    if options.close_on_eose
+                        && self
+                            .connections
+                            .iter()
+                            .filter(|c| c.connected)
+                            .all(|c| stored.contains(&c.relay) || refused.contains(&c.relay))
+                    {
+                        break Ok(());
+                    }
+                }
+                Ok(Some(msg)) => self.set_aside(&mut deferred, msg),
+                Ok(None) => std::thread::sleep(POLL_INTERVAL),
+                // one odd message must not fail the whole query
+                Err(e @ (Error::RawRelayMessage | Error::RelayMessage)) => {
+                    log::warn!("[{}] query(): skipping message: {:?}", self.name(), e);
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        for msg in deferred.into_iter().rev() {
+            self.pending.push_front(msg);
+        }
+        // whatever the outcome, or the REQ would be replayed on reconnection
+        if self.finished.len() == MAX_FINISHED {
+            self.finished.pop_front();
+        }
+        self.finished.push_back(id.clone());
+        let closed = self.close(id);
+        result?;
+        closed?;
+        match options.order {
+            Some(Order::Ascending) => events.sort_by_key(|e| e.created_at),
+            Some(Order::Descending) => events.sort_by_key(|e| std::cmp::Reverse(e.created_at)),
+            None => {}
+        }
+        Ok(events)
+    }
+
+    /// Ask the relays how many events match `filters` (NIP-45) without
+    /// downloading them, waiting up to `timeout` for every relay to answer.
+    /// With several relays the highest COUNT is returned. Other relay
+    /// messages received meanwhile are kept for `try_receive()`. A relay
+    /// without NIP-45 may answer with a CLOSED or a NOTICE: if no relay sent
+    /// a COUNT, this returns `Error::SubscriptionClosed` or
+    /// `Error::CountUnsupported` with that notice.
+    pub fn count(&mut self, filters: Vec<Filter>, timeout: Duration) -> Result<usize, Error> {
+        self.is_connected()?;
+        let id = SubscriptionId::generate();
+        self.send_priority(ClientMessage::count(id.clone(), filters))?;
+        let deadline = Instant::now() + timeout;
+        let mut deferred = Vec::new();
+        let mut answered = HashSet::new();
+        let mut counted: Option<usize> = None;
+        let mut closed = None;
+        let mut notice = None;
+        let result = loop {
+            let all_answered = self
+                .connections
+                .iter()
+                .filter(|c| c.connected)
+                .all(|c| answered.contains(&c.relay));
+            if all_answered || Instant::now() >= deadline {
+                break match (counted, closed, notice) {
+                    (Some(count), _, _) => Ok(count),
+                    (None, Some(e), _) => Err(e),
+                    (None, None, Some(notice)) => Err(Error::CountUnsupported(notice)),
+                    (None, None, None) => Err(Error::CountTimeout),
+                };
+            }
+            match self.next_relay_message() {
+                Ok(Some((
+                    relay,
+                    RelayMessage::Count {
+                        subscription_id,
+                        count,
+                    },
+                ))) if subscription_id == id => {
+                    answered.insert(relay);
+                    counted = counted.max(Some(count));
+                }
+                Ok(Some((
+                    relay,
+                    RelayMessage::Closed {
+                        subscription_id,
+                        message,
+                    },
+                ))) if subscription_id == id => {
+                    answered.insert(relay);
+                    closed.get_or_insert(Error::SubscriptionClosed {
+                        id: subscription_id,
+                        message,
+                    });
+                }
+                Ok(Some((relay, RelayMessage::Notice { message }))) => {
+                    // notices don't name the request, take it as the answer
+                    // of that relay
+                    answered.insert(relay.clone());
+                    notice.get_or_insert_with(|| message.clone());
+                    // keep it for `try_notice()`
+                    self.set_aside(&mut deferred, (relay, RelayMessage::Notice { message }));
+                }
+                Ok(Some(msg)) => self.set_aside(&mut deferred, msg),
+                Ok(None) => std::thread::sleep(POLL_INTERVAL),
+                Err(e) => break Err(e),
+            }
+        };
+        for msg in deferred.into_iter().rev() {
+            self.pending.push_front(msg);
+        }
+        result
+    }
+
+    /// Collect the events matching `filters` until EOSE or `timeout`, see
+    /// `query()`.
+    pub fn fetch_events(
+        &mut self,
+        filters: Vec<Filter>,
+        timeout: Duration,
+    ) -> Result<Vec<Event>, Error> {
+        let options = QueryOptions {
+            timeout,
+            ..Default::default()
+        };
+        self.query(filters, options)
+    }
+
+    /// Time from sending the REQ to the first event (or to EOSE if the relays
+    /// had no stored event) and to EOSE, available once every relay sent EOSE.
+    pub fn subscription_latency(&self, id: &SubscriptionId) -> Option<(Duration, Duration)> {
+        let latency = self.latency.get(id)?;
+        Some((latency.first_event?, latency.eose?))
+    }
+
+    /// Average processing time of the last received events, `None` until an
+    /// event has been received or if `WsClientBuilder::measure_processing()`
+    /// is not enabled.
+    pub fn processing_time(&self) -> Option<Duration> {
+        self.processing.as_ref()?.average()
+    }
+
+    /// Number of events dropped because of `WsClientBuilder::strict_subscriptions()`.
+    pub fn dropped_events(&self) -> usize {
+        self.stats.dropped_events.load(Ordering::Relaxed) as usize
+    }
+
+    /// Number of events received for subscriptions the relay had CLOSED.
+    pub fn late_events(&self) -> usize {
+        self.stats.late_events.load(Ordering::Relaxed) as usize
+    }
+
+    /// Fetch the event with `id`, looking it up in the local store first when
+    /// one is configured.
+    pub fn fetch_event(&mut self, id: EventId, timeout: Duration) -> Result<Option<Event>, Error> {
+        #[cfg(feature = "store")]
+        if let Some(event) = self.store.as_ref().and_then(|s| s.get(&id)) {
+            #[allow(deprecated)]
+            return if event.kind() == Kind::EncryptedDirectMessage {
+                self.decrypt_dm(event).map(Some)
+            } else {
+                Ok(Some(event))
+            };
+        }
+        let options = QueryOptions {
+            limit: Some(1),
+            timeout,
+            ..Default::default()
+        };
+        let mut events = self.query(vec![Filter::new().id(id)], options)?;
+        Ok(events.pop())
+    }
+
+    // only live events (received after EOSE) are a meaningful sample
+    fn sample_clock(&mut self, event: &Event) {
+        let offset = Timestamp::now().as_u64() as i64 - event.created_at.as_u64() as i64;
+        self.clock_offsets.push_back(offset);
+        if self.clock_offsets.len() > CLOCK_SKEW_SAMPLES {
+            self.clock_offsets.pop_front();
+        }
+    }
+
+    /// How far the local clock seems ahead of the network, estimated from
+    /// the smallest delay between `created_at` and reception among the
+    /// last live events. `None` until a live event has been received.
+    pub fn estimated_clock_skew(&self) -> Option<Duration> {
+        let offset = self.clock_offsets.iter().min()?;
+        Some(Duration::from_secs((*offset).max(0) as u64))
+    }
+
+    /// Kinds of all the events received since connection or last
+    /// `clear_seen_kinds()`, in ascending order.
+    pub fn seen_kinds(&self) -> Vec<Kind> {
+        let mut kinds: Vec<Kind> = self.seen_kinds.iter().copied().collect();
+        kinds.sort_by_key(|k| k.as_u16());
+        kinds
+    }
+
+    pub fn clear_seen_kinds(&mut self) {
+        self.seen_kinds.clear();
+    }
+
+    pub fn stop(&mut self) {
+        for connection in self.connections.iter_mut().filter(|c| c.connected) {
+            connection.connected = false;
+            connection.stop();
+        }
+    }
+
+    /// Stop the client and wait up to 2s for the listener threads to exit,
+    /// the ones still running after that are left detached.
+    pub fn shutdown(mut self) {
+        self.stop();
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        for i in 0..self.connections.len() {
+            let Some(handle) = self.connections[i].handle.take() else {
+                continue;
+            };
+            while !handle.is_finished() && Instant::now() < deadline {
+                std::thread::sleep(POLL_INTERVAL);
+            }
+            let label = self.relay_label(&self.connections[i].relay);
+            if handle.is_finished() {
+                if handle.join().is_err() {
+                    log::error!("[{}] shutdown(): listener panicked", label);
+                }
+            } else {
+                log::warn!("[{}] shutdown(): listener still running", label);
+            }
+        }
+    }
+
+    /// The first relay the client connected to.
+    pub fn get_relay(&self) -> String {
+        self.connections
+            .first()
+            .map(|c| c.relay.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn get_relays(&self) -> Vec<String> {
+        self.connections.iter().map(|c| c.relay.clone()).collect()
+    }
+
+    pub fn get_label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    fn name(&self) -> &str {
+        self.label
+            .as_deref()
+            .or_else(|| self.connections.first().map(|c| c.relay.as_str()))
+            .unwrap_or_default()
+    }
+
+    /// When the last pong was received, from any relay. Updated as messages
+    /// are received with `try_receive()` and the like.
+    pub fn last_pong(&self) -> Option<SystemTime> {
+        self.connections
+            .iter()
+            .filter(|c| c.connected)
+            .filter_map(|c| c.last_pong)
+            .max()
+    }
+
+    /// Round trip of the last ping, the lowest one among the relays.
+    pub fn latency(&self) -> Option<Duration> {
+        self.connections
+            .iter()
+            .filter(|c| c.connected)
+            .filter_map(|c| c.latency)
+            .min()
+    }
+
+    /// Ok while at least one relay is connected.
+    pub fn is_connected(&self) -> Result<(), Error> {
+        if self.connections.iter().any(|c| c.connected) {
+            Ok(())
+        } else {
+            Err(Error::NotConnected)
+        }
+    }
+
+    pub fn get_keys(&self) -> Result<&Keys, Error> {
+        self.keys.as_ref().ok_or(Error::KeysMissing)
+    }
+
+    /// Whether the client can sign, e.g. to enable posting in a UI.
+    pub fn signer_kind(&self) -> SignerKind {
+        match self.keys {
+            Some(_) => SignerKind::Local,
+            None => SignerKind::ReadOnly,
+        }
+    }
+
+    /// Sign `builder` with the client keys without sending it, e.g. to show
+    /// the event before posting it with `post_signed_event()`, under the same
+    /// id.
+    pub fn preview_event(&self, builder: EventBuilder) -> Result<Event, Error> {
+        self.sign(builder, self.get_keys()?)
+    }
+
+    fn sign(&self, builder: EventBuilder, keys: &Keys) -> Result<Event, Error> {
+        self.stamp(builder, keys.public_key())
+            .to_event(keys)
+            .map_err(|_| Error::SignEvent)
+    }
+
+    // date the event to the relay clock if compensating the skew, unless the
+    // caller pinned `created_at`
+    fn stamp(&self, mut builder: EventBuilder, pubkey: PublicKey) -> EventBuilder {
+        if self.compensate_clock_skew && !is_pinned(&builder, pubkey) {
+            if let Some(skew) = self
+                .estimated_clock_skew()
+                .filter(|s| s.as_secs() > CLOCK_SKEW_THRESHOLD)
+            {
+                let created_at = Timestamp::now() - Timestamp::from_secs(skew.as_secs());
+                builder = builder.custom_created_at(created_at);
+            }
+        }
+        builder
+    }
+
+    /// Answer the last AUTH challenge received from each relay (NIP-42).
+    pub fn authenticate(&mut self) -> Result<(), Error> {
+        self.is_connected()?;
+        if self.auth_challenge.is_empty() {
+            return Err(Error::NoAuthChallenge);
+        }
+        for (relay, challenge) in self.auth_challenge.clone() {
+            self.authenticate_relay(relay, challenge)?;
+        }
+        Ok(())
+    }
+
+    // the challenge is echoed verbatim in the `challenge` tag
+    fn authenticate_relay(&mut self, relay: String, challenge: String) -> Result<(), Error> {
+        let event = match self.last_auth.get(&relay) {
+            Some((c, event)) if self.cache_auth && *c == challenge => event.clone(),
+            _ => {
+                let url = nostr::Url::parse(&relay)?;
+                EventBuilder::auth(challenge.clone(), url)
+                    .to_event(self.get_keys()?)
+                    .map_err(|_| Error::SignEvent)?
+            }
+        };
+        self.hooks.auth(&event);
+        self.last_auth_relay = Some(relay.clone());
+        self.last_auth
+            .insert(relay.clone(), (challenge, event.clone()));
+        let msg = ClientMessage::auth(event);
+        log::debug!("[{}] authenticate() msg: {:?}", self.name(), msg);
+        self.send_priority_to(&relay, msg)
+    }
+
+    fn is_auth_event(&self, id: &EventId) -> bool {
+        self.last_auth.values().any(|(_, event)| event.id == *id)
+    }
+
+    pub fn last_auth_event(&self) -> Option<Event> {
+        let relay = self.last_auth_relay.as_ref()?;
+        self.last_auth.get(relay).map(|(_, event)| event.clone())
+    }
+
+    pub fn post_event(&mut self, event: EventBuilder) -> Result<(), Error> {
+        self.is_connected()?;
+        let event = self.sign(event, self.get_keys()?)?;
+        self.send_event(event).map(|_| ())
+    }
+
+    /// Post an event already signed, e.g. by `preview_event()`.
+    pub fn post_signed_event(&mut self, event: Event) -> Result<EventId, Error> {
+        self.is_connected()?;
+        self.send_event(event)
+    }
+
+    /// Like `post_event` but sign with `keys` instead of the client keys.
+    pub fn post_event_with_keys(
+        &mut self,
+        event: EventBuilder,
+        keys: &Keys,
+    ) -> Result<EventId, Error> {
+        self.is_connected()?;
+        let event = self.sign(event, keys)?;
+        self.send_event(event)
+    }
+
+    /// Post a plain text note (kind 1), use `post_event` for tagged notes.
+    pub fn publish_note<T: Into<String>>(&mut self, content: T) -> Result<(), Error> {
+        self.post_event(EventBuilder::text_note(content, []))
+    }
+
+    /// Publish our profile (kind 0). The kind is replaceable: relays keep
+    /// only the latest, so `metadata` overwrites the prior profile rather
+    /// than being merged with it.
+    pub fn publish_metadata(&mut self, metadata: Metadata) -> Result<(), Error> {
+        self.post_event(EventBuilder::metadata(&metadata))
+    }
+
+    /// Publish our follow list (NIP-02, kind 3) as `(pubkey, relay url,
+    /// petname)` entries. The kind is replaceable: `contacts` must be the
+    /// full list, contacts left out are unfollowed.
+    pub fn set_contacts(
+        &mut self,
+        contacts: Vec<(PublicKey, Option<String>, Option<String>)>,
+    ) -> Result<(), Error> {
+        let tags = contacts.into_iter().map(|(pubkey, relay, petname)| {
+            let mut values = vec![pubkey.to_hex()];
+            match (relay, petname) {
+                (relay, Some(petname)) => {
+                    values.push(relay.unwrap_or_default());
+                    values.push(petname);
+                }
+                (Some(relay), None) => values.push(relay),
+                (None, None) => {}
+            }
+            Tag::custom(
+                TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::P)),
+                values,
+            )
+        });
+        self.post_event(EventBuilder::new(Kind::ContactList, "", tags))
+    }
+
+    /// Publish our relay list (NIP-65, kind 10002), the relays we read from
+    /// and write to. Replaceable like the follow list: `relays` must be the
+    /// full list.
+    pub fn publish_relay_list(
+        &mut self,
+        relays: Vec<(String, Option<RelayMarker>)>,
+    ) -> Result<(), Error> {
+        let mut tags = Vec::new();
+        for (url, marker) in relays {
+            let mut values = vec![normalize_relay_url(&url)?];
+            values.extend(marker.map(|m| m.as_str().to_string()));
+            tags.push(Tag::custom(
+                TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::R)),
+                values,
+            ));
+        }
+        self.post_event(EventBuilder::new(Kind::RelayList, "", tags))
+    }
+
+    /// Fetch the latest relay list published by `pubkey`, empty if none was
+    /// found before `timeout`.
+    pub fn fetch_relay_list(
+        &mut self,
+        pubkey: PublicKey,
+        timeout: Duration,
+    ) -> Result<Vec<(String, Option<RelayMarker>)>, Error> {
+        let filter = Filter::new().author(pubkey).kind(Kind::RelayList);
+        let events = self.fetch_events(vec![filter], timeout)?;
+        // each relay may hold a different version
+        let Some(latest) = events.iter().max_by_key(|e| e.created_at) else {
+            return Ok(Vec::new());
+        };
+        let relays = latest
+            .tags
+            .iter()
+            .filter_map(|t| match t.as_slice() {
+                [kind, url, marker @ ..] if kind == "r" => {
+                    let marker = match marker.first().map(String::as_str) {
+                        Some("read") => Some(RelayMarker::Read),
+                        Some("write") => Some(RelayMarker::Write),
+                        _ => None,
+                    };
+                    Some((url.clone(), marker))
+                }
+                _ => None,
+            })
+            .collect();
+        Ok(relays)
+    }
+
+    /// Mine a NIP-13 proof of work of `difficulty` leading zero bits for
+    /// `event` then post it, for relays requiring one.
+    pub fn post_event_with_pow(
+        &mut self,
+        event: EventBuilder,
+        difficulty: u8,
+    ) -> Result<(), Error> {
+        self.post_event_with_pow_timeout(event, difficulty, None)
+    }
+
+    /// Like `post_event_with_pow` but give up with `Error::PowTimeout` if no
+    /// proof of work was found after `timeout`.
+    pub fn post_event_with_pow_timeout(
+        &mut self,
+        event: EventBuilder,
+        difficulty: u8,
+        timeout: Option<Duration>,
+    ) -> Result<(), Error> {
+        self.is_connected()?;
+        let keys = self.get_keys()?.clone();
+        let builder = self.stamp(event, keys.public_key());
+        let stop = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = mpsc::channel();
+        let worker_stop = stop.clone();
+        std::thread::spawn(move || {
+            if let Some(event) = mine(builder, keys.public_key(), difficulty, &worker_stop) {
+                let _ = sender.send(event);
+            }
+        });
+        let mined = match timeout {
+            Some(timeout) => receiver.recv_timeout(timeout).ok(),
+            None => receiver.recv().ok(),
+        };
+        let Some(unsigned) = mined else {
+            stop.store(true, Ordering::Relaxed);
+            return Err(Error::PowTimeout);
+        };
+        let event = unsigned.sign(&keys).map_err(|_| Error::SignEvent)?;
+        self.send_event(event).map(|_| ())
+    }
+
+    /// Publish an addressable event (NIP-33, kinds 30000-39999), replacing
+    /// any `d` tag in `tags` by `identifier`. Returns its `kind:pubkey:d`
+    /// coordinate, relays replace it by coordinate and
+    /// `delete_addressable()` deletes by it.
+    pub fn publish_addressable(
+        &mut self,
+        kind: Kind,
+        identifier: String,
+        content: String,
+        tags: Vec<Tag>,
+    ) -> Result<String, Error> {
+        if !kind.is_parameterized_replaceable() {
+            let pubkey = self.pubkey()?;
+            return Err(Error::InvalidCoordinate(format!(
+                "{}:{}:{}",
+                kind.as_u16(),
+                pubkey,
+                identifier
+            )));
+        }
+        let tags = tags
+            .into_iter()
+            .filter(|t| t.as_slice().first().map(String::as_str) != Some("d"))
+            .chain([Tag::identifier(identifier)]);
+        self.is_connected()?;
+        let event = self.sign(EventBuilder::new(kind, content, tags), self.get_keys()?)?;
+        let coordinate = addressable_coordinate(&event).expect("addressable kind");
+        self.send_event(event)?;
+        Ok(coordinate)
+    }
+
+    /// Ask the relays to delete our events `event_ids` (NIP-09), with an
+    /// optional `reason`, see `delete_addressable()` for addressable events.
+    pub fn delete_event(
+        &mut self,
+        event_ids: Vec<EventId>,
+        reason: Option<String>,
+    ) -> Result<(), Error> {
+        let tags = event_ids.into_iter().map(Tag::event).collect();
+        self.post_deletion(tags, reason)
+    }
+
+    /// Like `delete_event` but for (parameterized) replaceable events,
+    /// addressed by their `kind:pubkey:d` coordinates, see
+    /// `addressable_coordinate()`.
+    pub fn delete_addressable(
+        &mut self,
+        coordinates: Vec<String>,
+        reason: Option<String>,
+    ) -> Result<(), Error> {
+        let tags = coordinates
+            .into_iter()
+            .map(|c| {
+                Coordinate::from_str(&c)
+                    .map(Tag::coordinate)
+                    .map_err(|_| Error::InvalidCoordinate(c))
+            })
+            .collect::<Result<_, _>>()?;
+        self.post_deletion(tags, reason)
+    }
+
+    fn post_deletion(&mut self, tags: Vec<Tag>, reason: Option<String>) -> Result<(), Error> {
+        let deletion = EventBuilder::new(Kind::EventDeletion, reason.unwrap_or_default(), tags);
+        self.post_event(deletion)
+    }
+
+    /// React to `event` (NIP-25) with `reaction`, e.g. "+", "-" or an
+    /// emoji, "+" (like) if empty.
+    pub fn react(&mut self, event: &Event, reaction: &str) -> Result<(), Error> {
+        let reaction = if reaction.is_empty() { "+" } else { reaction };
+        self.post_event(EventBuilder::reaction(event, reaction))
+    }
+
+    /// Reply to `replying_to` with a text note carrying the NIP-10 `root`
+    /// and `reply` markers, the parent becoming the root if it has none. The
+    /// parent author and the pubkeys it mentions are tagged.
+    pub fn reply(&mut self, content: String, replying_to: &Event) -> Result<(), Error> {
+        let parent = replying_to.id.to_hex();
+        let mut tags = match thread_root(replying_to) {
+            Some((root, relay)) => vec![
+                marked_e_tag(root, relay, "root"),
+                marked_e_tag(&parent, "", "reply"),
+            ],
+            None => vec![marked_e_tag(&parent, "", "root")],
+        };
+        let mut pubkeys = vec![replying_to.pubkey];
+        for tag in replying_to.tags.iter().map(Tag::as_slice) {
+            if let [kind, pubkey, ..] = tag {
+                if kind == "p" {
+                    if let Ok(pubkey) = PublicKey::from_hex(pubkey) {
+                        if !pubkeys.contains(&pubkey) {
+                            pubkeys.push(pubkey);
+                        }
+                    }
+                }
+            }
+        }
+        tags.extend(pubkeys.into_iter().map(Tag::public_key));
+        self.post_event(EventBuilder::text_note(content, tags))
+    }
+
+    /// Sign and post every event of `events`, going on after a failure. The
+    /// first error is returned once all were tried.
+    pub fn post_events(&mut self, events: Vec<EventBuilder>) -> Result<(), Error> {
+        self.is_connected()?;
+        let mut error = None;
+        for event in events {
+            let result = self
+                .sign(event, self.get_keys()?)
+                .and_then(|event| self.send_event(event));
+            if let Err(e) = result {
+                log::warn!(
+                    "[{}] post_events(): fail to post event: {:?}",
+                    self.name(),
+                    e
+                );
+                error.get_or_insert(e);
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Post every event of `events` then wait up to `timeout` for their OK,
+    /// returning the result of each so the rejected ones can be retried.
+    /// Events not acknowledged in time fail with `Error::PublishTimeout`, the
+    /// ones that can't be signed with `Error::SignEvent` under a zero id.
+    /// Other relay messages received meanwhile are kept for `try_receive()`.
+    pub fn publish_batch_and_confirm(
+        &mut self,
+        events: Vec<EventBuilder>,
+        timeout: Duration,
+    ) -> Result<Vec<(EventId, Result<(), Error>)>, Error> {
+        self.is_connected()?;
+        self.get_keys()?;
+        let mut results = Vec::with_capacity(events.len());
+        for event in events {
+            let event = match self.sign(event, self.get_keys()?) {
+                Ok(event) => event,
+                Err(e) => {
+                    results.push((EventId::all_zeros(), Some(Err(e))));
+                    continue;
+                }
+            };
+            let id = event.id;
+            // `None` while waiting for the OK
+            results.push((id, self.send_event(event).err().map(Err)));
+        }
+        let deadline = Instant::now() + timeout;
+        let mut deferred = Vec::new();
+        while Instant::now() < deadline && results.iter().any(|(_, r)| r.is_none()) {
+            match self.next_relay_message() {
+                Ok(Some((
+                    relay,
+                    RelayMessage::Ok {
+                        event_id,
+                        status,
+                        message,
+                    },
+                ))) => {
+                    let Some((_, result)) = results
+                        .iter_mut()
+                        .find(|(id, r)| *id == event_id && r.is_none())
+                    else {
+                        self.set_aside(
+                            &mut deferred,
+                            (
+                                relay,
+                                RelayMessage::Ok {
+                                    event_id,
+                                    status,
+                                    message,
+                                },
+                            ),
+                        );
+                        continue;
+                    };
+                    self.in_flight.remove(&event_id);
+                    *result = Some(if status {
+                        Ok(())
+                    } else {
+                        Err(Error::Rejected(message))
+                    });
+                }
+                Ok(Some(msg)) => self.set_aside(&mut deferred, msg),
+                Ok(None) => std::thread::sleep(POLL_INTERVAL),
+                Err(e) => {
+                    log::error!(
+                        "[{}] publish_batch_and_confirm(): fail to receive: {:?}",
+                        self.name(),
+                        e
+                    );
+                    break;
+                }
+            }
+        }
+        for msg in deferred.into_iter().rev() {
+            self.pending.push_front(msg);
+        }
+        Ok(results
+            .into_iter()
+            .map(|(id, r)| (id, r.unwrap_or(Err(Error::PublishTimeout))))
+            .collect())
+    }
+
+    /// Sign `builder` once, send it to every connected relay and wait up to
+    /// `timeout` for the OK of each of them. Other relay messages received
+    /// meanwhile are kept for `try_receive()`.
+    pub fn publish_to_all(
+        &mut self,
+        builder: EventBuilder,
+        timeout: Duration,
+    ) -> Result<PublishReport, Error> {
+        self.is_connected()?;
+        let event = self.sign(builder, self.get_keys()?)?;
+        let id = self.send_event_with(event, PublishMode::Broadcast)?;
+        let mut waiting: HashSet<String> = self
+            .connections
+            .iter()
+            .filter(|c| c.connected)
+            .map(|c| c.relay.clone())
+            .collect();
+        let mut accepted = Vec::new();
+        let mut rejected = Vec::new();
+        let deadline = Instant::now() + timeout;
+        let mut deferred = Vec::new();
+        while Instant::now() < deadline && !waiting.is_empty() {
+            match self.next_relay_message() {
+                Ok(Some((
+                    relay,
+                    RelayMessage::Ok {
+                        event_id,
+                        status,
+                        message,
+                    },
+                ))) if event_id == id && waiting.contains(&relay) => {
+                    waiting.remove(&relay);
+                    if status {
+                        accepted.push(relay);
+                    } else {
+                        log::warn!(
+                            "[{}] event {} rejected by {}: {}",
+                            self.name(),
+                            id,
+                            relay,
+                            message
+                        );
+                        rejected.push((relay, message));
+                    }
+                }
+                Ok(Some(msg)) => self.set_aside(&mut deferred, msg),
+                Ok(None) => std::thread::sleep(POLL_INTERVAL),
+                Err(e) => {
+                    log::error!(
+                        "[{}] publish_to_all(): fail to receive: {:?}",
+                        self.name(),
+                        e
+                    );
+                    break;
+                }
+            }
+        }
+        for msg in deferred.into_iter().rev() {
+            self.pending.push_front(msg);
+        }
+        self.in_flight.remove(&id);
+        let timed_out = self
+            .connections
+            .iter()
+            .filter(|c| waiting.contains(&c.relay))
+            .map(|c| c.relay.clone())
+            .collect();
+        Ok(PublishReport {
+            id,
+            accepted,
+            rejected,
+            timed_out,
+        })
+    }
+
+    /// Post `event` then wait up to `timeout` for the relay OK, returning
+    /// whether the event was accepted. With several relays the first OK
+    /// received is returned. Other relay messages received meanwhile are kept
+    /// for `try_receive()`.
+    pub fn publish_and_confirm(
+        &mut self,
+        event: EventBuilder,
+        timeout: Duration,
+    ) -> Result<bool, Error> {
+        self.is_connected()?;
+        let event = self.sign(event, self.get_keys()?)?;
+        let id = self.send_event(event)?;
+        let deadline = Instant::now() + timeout;
+        let mut deferred = Vec::new();
+        let result = loop {
+            if Instant::now() >= deadline {
+                break Err(Error::PublishTimeout);
+            }
+            match self.next_relay_message() {
+                Ok(Some((
+                    _,
+                    RelayMessage::Ok {
+                        event_id,
+                        status,
+                        message,
+                    },
+                ))) if event_id == id => {
+                    self.in_flight.remove(&id);
+                    if !status {
+                        log::warn!("[{}] event {} rejected: {}", self.name(), id, message);
+                    }
+                    break Ok(status);
+                }
+                Ok(Some(msg)) => self.set_aside(&mut deferred, msg),
+                Ok(None) => std::thread::sleep(POLL_INTERVAL),
+                Err(e) => break Err(e),
+            }
+        };
+        for msg in deferred.into_iter().rev() {
+            self.pending.push_front(msg);
+        }
+        result
+    }
+
+    /// Read newline-delimited JSON events from `reader` (as written by
+    /// `export_events()`) and send the ones with a valid signature to the
+    /// relay as is. Invalid lines are logged and skipped. Returns the ids of
+    /// the events sent, the import stops at the first send failure.
+    pub fn import_and_broadcast<R: BufRead>(&mut self, reader: R) -> Result<Vec<EventId>, Error> {
+        self.is_connected()?;
+        let mut sent = Vec::new();
+        for (n, line) in reader.lines().enumerate() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    log::warn!(
+                        "[{}] import: fail to read line {}: {:?}",
+                        self.name(),
+                        n + 1,
+                        e
+                    );
+                    continue;
+                }
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event = match Event::from_json(&line) {
+                Ok(event) => event,
+                Err(e) => {
+                    log::warn!(
+                        "[{}] import: line {} is not an event: {:?}",
+                        self.name(),
+                        n + 1,
+                        e
+                    );
+                    continue;
+                }
+            };
+            if let Err(e) = event.verify() {
+                log::warn!(
+                    "[{}] import: event {} at line {} is invalid: {:?}",
+                    self.name(),
+                    event.id,
+                    n + 1,
+                    e
+                );
+                continue;
+            }
+            match self.send_event(event) {
+                Ok(id) => sent.push(id),
+                Err(Error::MissingIdentifier(kind)) => {
+                    log::warn!(
+                        "[{}] import: addressable event of kind {} at line {} has no `d` tag",
+                        self.name(),
+                        kind,
+                        n + 1
+                    );
+                }
+                Err(e) => {
+                    // the events already sent are on the relays, report them
+                    log::error!(
+                        "[{}] import: stop at line {} after {} events: {:?}",
+                        self.name(),
+                        n + 1,
+                        sent.len(),
+                        e
+                    );
+                    break;
+                }
+            }
+        }
+        Ok(sent)
+    }
+
+    fn send_event(&mut self, event: Event) -> Result<EventId, Error> {
+        self.send_event_with(event, self.publish_mode)
+    }
+
+    fn send_event_with(&mut self, event: Event, mode: PublishMode) -> Result<EventId, Error> {
+        if event.kind.is_parameterized_replaceable() && identifier(&event).is_none() {
+            if self.strict_addressable {
+                return Err(Error::MissingIdentifier(event.kind));
+            }
+            log::warn!(
+                "[{}] posting addressable event {} without `d` tag, it will replace the one with an empty identifier",
+                self.name(),
+                event.id
+            );
+        }
+        let size = content_size(&event);
+        if size > LARGE_CONTENT {
+            log::warn!(
+                "[{}] posting event {} with a {} bytes content, relays may reject it",
+                self.name(),
+                event.id,
+                size
+            );
+        }
+        let (id, kind) = (event.id, event.kind);
+        let msg = ClientMessage::event(event);
+        log::debug!("[{}] _post_event() msg: {:?}", self.name(), msg);
+        match mode {
+            PublishMode::Broadcast => self.send_raw(msg.as_json())?,
+            PublishMode::RoundRobin => self.send_round_robin(msg.as_json())?,
+        }
+        if self.in_flight.len() < MAX_IN_FLIGHT {
+            self.in_flight.insert(id, kind);
+        }
+        Ok(id)
+    }
+}
+
+impl Drop for WsClient {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn keys_from_seed(seed: [u8; 32]) -> Result<Keys, Error> {
+    let secret_key = SecretKey::from_slice(&seed).map_err(|_| Error::KeyGeneration)?;
+    Ok(Keys::new(secret_key))
+}
+
+fn open(url: &str, label: &str, options: &ConnectOptions) -> Result<Socket, Error> {
+    let mut request = url.into_client_request()?;
+    let mut headers = vec![("User-Agent".to_string(), options.user_agent.clone())];
+    headers.extend(options.headers.iter().cloned());
+    if let Some(token) = &options.auth_token {
+        headers.push(("Authorization".into(), format!("Bearer {token}")));
+    }
+    for (name, value) in headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|e| tungstenite::Error::HttpFormat(e.into()))?;
+        let value =
+            HeaderValue::from_str(&value).map_err(|e| tungstenite::Error::HttpFormat(e.into()))?;
+        request.headers_mut().insert(name, value);
+    }
+    let tls = tls_connector(url, options)?;
+    let stream = match options.proxy {
+        Some(proxy) => proxy_stream(url, proxy, tls)?,
+        None => {
+            let onion = Url::parse(url)?
+                .host_str()
+                .is_some_and(|host| host.ends_with(".onion"));
+            if onion {
+                return Err(Error::ProxyRequired);
+            }
+            resolve(url)?;
+            direct_stream(url, tls)?
+        }
+    };
+    // the stream is still blocking, the handshake can't be interrupted
+    let client = match tungstenite::client::client(request, stream) {
+        Ok((client, _)) => client,
+        Err(HandshakeError::Failure(e)) => return Err(e.into()),
+        Err(HandshakeError::Interrupted(_)) => {
+            return Err(tungstenite::Error::Io(ErrorKind::WouldBlock.into()).into())
+        }
+    };
+    let tcp = client.get_ref().tcp();
+    if let Err(e) = tcp.set_nodelay(options.tcp_nodelay) {
+        log::warn!("[{}] connect(): fail to set TCP_NODELAY: {:?}", label, e);
+    }
+    if let Some(idle) = options.tcp_keepalive {
+        let keepalive = TcpKeepalive::new().with_time(idle);
+        if let Err(e) = SockRef::from(tcp).set_tcp_keepalive(&keepalive) {
+            log::warn!("[{}] connect(): fail to set SO_KEEPALIVE: {:?}", label, e);
+        }
+    }
+    if let Err(e) = tcp.set_nonblocking(true) {
+        log::warn!(
+            "[{}] connect(): fail to set non-blocking mode ({:?}), fallback to read timeout",
+            label,
+            e
+        );
+        tcp.set_read_timeout(Some(READ_TIMEOUT))
+            .map_err(|_| Error::NonBlocking)?;
+    }
+    Ok(client)
+}
+
+// tunnel to the relay through the SOCKS5 proxy, letting the proxy resolve the
+// host
+fn proxy_stream(url: &str, proxy: SocketAddr, tls: TlsConnector) -> Result<Stream, Error> {
+    let url = Url::parse(url)?;
+    let host = url.host_str().ok_or(ParseError::EmptyHost)?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let tcp = Socks5Stream::connect(proxy, (host, port))
+        .map_err(Error::Proxy)?
+        .into_inner();
+    tls_stream(&url, tcp, tls)
+}
+
+fn direct_stream(url: &str, tls: TlsConnector) -> Result<Stream, Error> {
+    let url = Url::parse(url)?;
+    let host = url.host_str().ok_or(ParseError::EmptyHost)?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let tcp = TcpStream::connect((host, port)).map_err(tungstenite::Error::Io)?;
+    tls_stream(&url, tcp, tls)
+}
+
+// run the TLS handshake ourselves for wss relays, any failure is then an
+// `Error::Tls`
+fn tls_stream(url: &Url, tcp: TcpStream, tls: TlsConnector) -> Result<Stream, Error> {
+    if url.scheme() != "wss" {
+        return Ok(Stream::Plain(tcp));
+    }
+    let host = url.host_str().ok_or(ParseError::EmptyHost)?;
+    let tls = tls
+        .connect(host, tcp)
+        .map_err(|e| Error::Tls(e.to_string()))?;
+    Ok(Stream::Tls(tls))
+}
+
+fn tls_connector(url: &str, options: &ConnectOptions) -> Result<TlsConnector, Error> {
+    TlsConnector::builder()
+        .danger_accept_invalid_certs(options.insecure_tls.contains(url))
+        .build()
+        .map_err(|e| Error::Tls(e.to_string()))
+}
+
+/// Fetch the NIP-11 information document of the relay at `url` (supported
+/// NIPs, limitations, ...) over http(s), see `WsClient::relay_info()` to go
+/// through the client proxy.
+pub fn fetch_relay_info(url: &str) -> Result<RelayInformationDocument, Error> {
+    relay_info(url, &ConnectOptions::default())
+}
+
+fn relay_info(url: &str, options: &ConnectOptions) -> Result<RelayInformationDocument, Error> {
+    let relay = normalize_relay_url(url)?;
+    let url = Url::parse(&relay)?;
+    let host = url.host_str().ok_or(ParseError::EmptyHost)?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let host_header = match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    };
+    // HTTP/1.0 so the body is neither chunked nor kept alive
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: application/nostr+json\r\n\r\n",
+        url.path(),
+        host_header
+    );
+    let info_error = |e: std::io::Error| Error::RelayInfo(e.to_string());
+    let tcp = match options.proxy {
+        Some(proxy) => Socks5Stream::connect(proxy, (host, port))
+            .map_err(Error::Proxy)?
+            .into_inner(),
+        None if host.ends_with(".onion") => return Err(Error::ProxyRequired),
+        None => {
+            let addr = (host, port)
+                .to_socket_addrs()
+                .map_err(|_| Error::DnsResolution {
+                    host: host.to_string(),
+                })?
+                .next()
+                .ok_or_else(|| Error::DnsResolution {
+                    host: host.to_string(),
+                })?;
+            TcpStream::connect_timeout(&addr, RELAY_INFO_TIMEOUT).map_err(info_error)?
+        }
+    };
+    tcp.set_read_timeout(Some(RELAY_INFO_TIMEOUT))
+        .map_err(info_error)?;
+    tcp.set_write_timeout(Some(RELAY_INFO_TIMEOUT))
+        .map_err(info_error)?;
+    let response = if url.scheme() == "wss" {
+        let tls = tls_connector(&relay, options)?
+            .connect(host, tcp)
+            .map_err(|e| Error::Tls(e.to_string()))?;
+        http_exchange(tls, &request)
+    } else {
+        http_exchange(tcp, &request)
+    }
+    .map_err(info_error)?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| Error::RelayInfo("malformed http response".into()))?;
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(Error::RelayInfo(status.to_string()));
+    }
+    nostr::serde_json::from_str(body).map_err(|e| Error::RelayInfo(e.to_string()))
+}
+
+fn http_exchange<S: Read + Write>(mut stream: S, request: &str) -> std::io::Result<Vec<u8>> {
+    stream.write_all(request.as_bytes())?;
+    let mut response = Vec::new();
+    (&mut stream)
+        .take(MAX_RELAY_INFO + 1)
+        .read_to_end(&mut response)?;
+    if response.len() as u64 > MAX_RELAY_INFO {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "response too large",
+        ));
+    }
+    Ok(response)
+}
+
+/// Canonical form of a relay url, so the same relay always compares equal:
+/// lowercase host, no default port and no trailing slash. Fails with
+/// `Error::InvalidRelayUrl` if not a `ws://` or `wss://` url.
+pub fn normalize_relay_url(url: &str) -> Result<String, Error> {
+    // the parser already lowercases the host and drops the default port
+    let parsed = Url::parse(url)?;
+    if !matches!(parsed.scheme(), "ws" | "wss") || parsed.host_str().is_none() {
+        return Err(Error::InvalidRelayUrl(url.to_string()));
+    }
+    if parsed.query().is_some() || parsed.fragment().is_some() {
+        return Ok(parsed.to_string());
+    }
+    Ok(parsed.as_str().trim_end_matches('/').to_string())
+}
+
+// resolve the relay host beforehand so a typo'd domain doesn't surface as a
+// generic websocket IO error
+fn resolve(url: &str) -> Result<(), Error> {
+    let url = Url::parse(url)?;
+    if let Some(Host::Domain(host)) = url.host() {
+        let port = url.port_or_known_default().unwrap_or(80);
+        if (host, port).to_socket_addrs().is_err() {
+            return Err(Error::DnsResolution {
+                host: host.to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+// NOTICE content is free text: this only recognizes the usual wordings relays
+// use when they clamp a filter (e.g. "filter too broad", "limit capped to 500")
+// and may miss or misreport others.
+fn is_filter_narrowed(notice: &str) -> bool {
+    let notice = notice.to_lowercase();
+    let about_filter = notice.contains("filter") || notice.contains("limit");
+    about_filter
+        && [
+            "too broad",
+            "narrow",
+            "truncat",
+            "capped",
+            "clamp",
+            "reduced",
+        ]
+        .iter()
+        .any(|w| notice.contains(w))
+}
+
+/// Decrypt the content of a direct message received by `keys`, NIP-04 or
+/// NIP-44 depending on its payload.
+pub fn try_decrypt_dm(keys: &Keys, event: &Event) -> Result<String, Error> {
+    if event.kind != Kind::EncryptedDirectMessage {
+        return Err(Error::NotNip04);
+    }
+    if is_nip04_payload(&event.content) {
+        nip04::decrypt(keys.secret_key(), &event.pubkey, &event.content)
+            .map_err(|_| Error::Nip04Decrypt)
+    } else {
+        nip44::decrypt(keys.secret_key(), &event.pubkey, &event.content)
+            .map_err(|_| Error::Nip44Decrypt)
+    }
+}
+
+// NIP-04 payloads are `<base64>?iv=<base64>`, NIP-44 ones are plain base64
+// starting with a version byte
+fn is_nip04_payload(content: &str) -> bool {
+    content.contains("?iv=")
+}
+
+/// Size of the event content in bytes.
+pub fn content_size(event: &Event) -> usize {
+    event.content.len()
+}
+
+fn identifier(event: &Event) -> Option<&str> {
+    event
+        .tags
+        .iter()
+        .find(|t| t.as_slice().first().map(String::as_str) == Some("d"))
+        .map(|t| t.as_slice().get(1).map(String::as_str).unwrap_or_default())
+}
+
+// bump the `nonce` tag until the event id has `difficulty` leading zero bits
+fn mine(
+    builder: EventBuilder,
+    pubkey: PublicKey,
+    difficulty: u8,
+    stop: &AtomicBool,
+) -> Option<UnsignedEvent> {
+    let mut nonce = 0u128;
+    while !stop.load(Ordering::Relaxed) {
+        let event = builder
+            .clone()
+            .add_tags([Tag::pow(nonce, difficulty)])
+            .to_unsigned_event(pubkey);
+        if event
+            .id
+            .is_some_and(|id| nip13::get_leading_zero_bits(id.as_bytes()) >= difficulty)
+        {
+            return Some(event);
+        }
+        nonce += 1;
+    }
+    None
+}
+
+fn subscription_of(msg: &RelayMessage) -> Option<&SubscriptionId> {
+    match msg {
+        RelayMessage::Event {
+            subscription_id, ..
+        }
+        | RelayMessage::EndOfStoredEvents(subscription_id)
+        | RelayMessage::Closed {
+            subscription_id, ..
+        }
+        | RelayMessage::Count {
+            subscription_id, ..
+        } => Some(subscription_id),
+        _ => None,
+    }
+}
+
+// whether `created_at` was set with `EventBuilder::custom_created_at`: the
+// builder doesn't expose it, but pinning it again to the date it produces
+// only leaves it unchanged if it was already pinned
+fn is_pinned(builder: &EventBuilder, pubkey: PublicKey) -> bool {
+    let created_at = builder.clone().to_unsigned_event(pubkey).created_at;
+    builder.clone().custom_created_at(created_at) == *builder
+}
+
+// the id and relay hint of the `e` tag marked `root`, if any
+fn thread_root(event: &Event) -> Option<(&str, &str)> {
+    event.tags.iter().find_map(|t| match t.as_slice() {
+        [kind, id, relay, marker, ..] if kind == "e" && marker == "root" => {
+            Some((id.as_str(), relay.as_str()))
+        }
+        _ => None,
+    })
+}
+
+fn marked_e_tag(id: &str, relay: &str, marker: &str) -> Tag {
+    Tag::custom(
+        TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::E)),
+        [id, relay, marker],
+    )
+}
+
+/// Decode a NIP-19 `note1...` string into the event id.
+pub fn note_to_event_id(note: &str) -> Result<EventId, Error> {
+    EventId::from_bech32(note).map_err(|_| Error::InvalidBech32)
+}
+
+/// Decode a NIP-19 `nevent1...` string into the event id, dropping the
+/// relay and author hints.
+pub fn nevent_to_event_id(nevent: &str) -> Result<EventId, Error> {
+    Nip19Event::from_bech32(nevent)
+        .map(|nevent| nevent.event_id)
+        .map_err(|_| Error::InvalidBech32)
+}
+
+/// Returns the `kind:pubkey:d` coordinate of an addressable event (kinds
+/// 30000-39999), a missing `d` tag being treated as an empty identifier.
+pub fn addressable_coordinate(event: &Event) -> Option<String> {
+    if !event.kind.is_parameterized_replaceable() {
+        return None;
+    }
+    let identifier = identifier(event).unwrap_or_default();
+    Some(format!(
+        "{}:{}:{}",
+        event.kind.as_u16(),
+        event.pubkey,
+        identifier
+    ))
+}
+
+// send a Close frame and give the relay a moment to echo it
+fn close(client: &mut Socket, config: &ListenConfig) {
+    match client.close(None) {
+        Ok(_) => {}
+        // queued, flushed by the reads below
+        Err(tungstenite::Error::Io(e)) if is_would_block(&e) => {}
+        Err(e) => {
+            config.log(
+                log::Level::Debug,
+                format_args!("close(): fail to send Close: {:?}", e),
+            );
+            return;
+        }
+    }
+    let deadline = Instant::now() + CLOSE_TIMEOUT;
+    while Instant::now() < deadline {
+        match client.read() {
+            Ok(WsMessage::Close(_)) | Err(tungstenite::Error::ConnectionClosed) => return,
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e)) if is_would_block(&e) => {}
+            Err(_) => return,
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+// a read timeout reports TimedOut on some platforms
+fn is_would_block(e: &std::io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+// remember the subscriptions opened on the relay so they can be replayed
+// after a reconnection
+fn track_subscription(subscriptions: &mut HashMap<SubscriptionId, Message>, msg: &Message) {
+    match ClientMessage::from_json(msg) {
+        Ok(ClientMessage::Req {
+            subscription_id, ..
+        }) => {
+            subscriptions.insert(subscription_id, msg.clone());
+        }
+        Ok(ClientMessage::Close(subscription_id)) => {
+            subscriptions.remove(&subscription_id);
+        }
+        _ => {}
+    }
+}
+
+// wait then retry as told by the reconnection strategy, messages posted
+// meanwhile wait in the send queues; `None` if the strategy gives up or the
+// client is stopped
+fn reconnect(config: &ListenConfig) -> Option<Socket> {
+    let Some(strategy) = config.reconnect.as_ref() else {
+        config.notify(ConnectionState::Closed);
+        return None;
+    };
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let delay = match strategy.lock() {
+            Ok(mut strategy) => strategy.next_delay(attempt),
+            Err(_) => {
+                config.log(
+                    log::Level::Error,
+                    format_args!("reconnect(): strategy poisoned"),
+                );
+                None
+            }
+        };
+        let Some(delay) = delay else {
+            config.log(
+                log::Level::Error,
+                format_args!("reconnect(): giving up after {} attempts", attempt - 1),
+            );
+            config.notify(ConnectionState::Closed);
+            return None;
+        };
+        config.notify(ConnectionState::Reconnecting);
+        let deadline = Instant::now() + delay;
+        while Instant::now() < deadline {
+            if config.stopped.load(Ordering::Relaxed) {
+                config.notify(ConnectionState::Closed);
+                return None;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        config.log(
+            log::Level::Info,
+            format_args!("reconnecting, attempt {}", attempt),
+        );
+        match open(&config.relay, &config.label, &config.options) {
+            Ok(client) => {
+                Stats::incr(&config.stats.reconnects);
+                config.hooks.reconnected(&config.relay);
+                config.notify(ConnectionState::Connected);
+                return Some(client);
+            }
+            Err(e) => config.log(
+                log::Level::Warn,
+                format_args!("reconnect(): fail to connect: {:?}", e),
+            ),
+        }
+    }
+}
+
+pub fn listen(
+    mut client: Socket,
+    sender: SyncSender<RecvMsg>,
+    receiver: Receiver<SendMsg>,
+    priority: Receiver<Message>,
+    config: ListenConfig,
+) {
+    let mut last_ping = SystemTime::now();
+    let mut last_pong = SystemTime::now();
+    let mut ping_nonce = 0u8;
+    // pings not answered yet, by nonce
+    let mut pings: HashMap<u8, SystemTime> = HashMap::new();
+    let mut upload = config.bandwidth_limit.map(TokenBucket::new);
+    let mut download = config.bandwidth_limit.map(TokenBucket::new);
+    let mut outgoing = VecDeque::new();
+    let mut urgent = VecDeque::new();
+    let mut batch = Vec::new();
+    let mut held = VecDeque::new();
+    let mut subscriptions = HashMap::new();
+    let mut idle = IDLE_MIN;
+    config.notify(ConnectionState::Connected);
+    loop {
+        let mut wait = true;
+        let mut received_text = false;
+        let mut closed = false;
+        let paused = config.paused.load(Ordering::Relaxed);
+        if !paused && !held.is_empty() {
+            let _ = sender.send(RecvMsg::Batch(
+                config.relay.clone(),
+                held.drain(..).collect(),
+            ));
+        }
+        if config.stopped.load(Ordering::Relaxed) {
+            close(&mut client, &config);
+            config.notify(ConnectionState::Closed);
+            return;
+        }
+        // take one message at a time once the previous one is out, so a slow
+        // relay fills the bounded queues and pushes back on the client
+        if urgent.is_empty() {
+            if let Ok(m) = priority.try_recv() {
+                urgent.push_back(m);
+            }
+        }
+        let next = if outgoing.is_empty() {
+            receiver.try_recv()
+        } else {
+            Err(mpsc::TryRecvError::Empty)
+        };
+        match next {
+            Ok(m) => match m {
+                SendMsg::Msg(m) => {
+                    wait = false;
+                    outgoing.push_back(m);
+                }
+                SendMsg::Stop => {
+                    close(&mut client, &config);
+                    config.notify(ConnectionState::Closed);
+                    return;
+                }
+            },
+            Err(mpsc::TryRecvError::Empty) => {}
+            _ => {
+                close(&mut client, &config);
+                config.notify(ConnectionState::Closed);
+                return;
+            }
+        }
+
+        let has_outgoing = !urgent.is_empty() || !outgoing.is_empty();
+        if has_outgoing && upload.as_mut().is_none_or(TokenBucket::available) {
+            let m = match urgent.pop_front() {
+                Some(m) => {
+                    if config.reconnect.is_some() {
+                        track_subscription(&mut subscriptions, &m);
+                    }
+                    m
+                }
+                None => outgoing.pop_front().expect("not empty"),
+            };
+            wait = false;
+            if let Some(bucket) = upload.as_mut() {
+                bucket.consume(m.len());
+            }
+            match client.send(WsMessage::Text(m)) {
+                // a WouldBlock leaves it in the write buffer, flushed below
+                Ok(_) => Stats::incr(&config.stats.messages_sent),
+                Err(tungstenite::Error::Io(e)) if is_would_block(&e) => {
+                    Stats::incr(&config.stats.messages_sent)
+                }
+                Err(e) => config.log(
+                    log::Level::Error,
+                    format_args!("listen(): fail to send message: {:?}", e),
+                ),
+            }
+        }
+
+        match client.flush() {
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e)) if is_would_block(&e) => {}
+            Err(e) => config.log(
+                log::Level::Error,
+                format_args!("listen(): fail to flush: {:?}", e),
+            ),
+        }
+
+        let received = download
+            .as_mut()
+            .is_none_or(TokenBucket::available)
+            .then(|| client.read());
+
+        match received {
+            Some(Ok(m)) => {
+                wait = false;
+                if let Some(bucket) = download.as_mut() {
+                    bucket.consume(m.len());
+                }
+                match m {
+                    WsMessage::Text(m) => {
+                        config.log(log::Level::Debug, format_args!("recv text: {:?}", m));
+                        Stats::incr(&config.stats.messages_received);
+                        if config.reconnect.is_some() && m.starts_with("[\"CLOSED\"") {
+                            if let Ok(RelayMessage::Closed {
+                                subscription_id, ..
+                            }) = parse_relay_message(&m)
+                            {
+                                subscriptions.remove(&subscription_id);
+                            }
+                        }
+                        if paused {
+                            if held.len() == PAUSE_BUFFER {
+                                held.pop_front();
+                                config.log(
+                                    log::Level::Warn,
+                                    format_args!(
+                                        "listen(): paused buffer full, dropping oldest message"
+                                    ),
+                                );
+                            }
+                            held.push_back(m);
+                        } else if config.batch_size > 1 {
+                            received_text = true;
+                            batch.push(m);
+                        } else {
+                            let _ = sender.send(RecvMsg::Msg(config.relay.clone(), m));
+                        }
+                    }
+                    WsMessage::Binary(m) => {
+                        config.log(
+                            log::Level::Error,
+                            format_args!("listen() unexpected binary message {:?}", m),
+                        );
+                    }
+                    WsMessage::Close(_) => {
+                        config.log(log::Level::Debug, format_args!("recv: Close "));
+                        closed = true;
+                    }
+                    // answered by tungstenite on the next read or write
+                    WsMessage::Ping(_) => {}
+                    WsMessage::Pong(nonce) => {
+                        Stats::incr(&config.stats.pongs);
+                        // an unsolicited pong does not prove the connection
+                        // is alive, a late one still does
+                        let sent = match nonce.as_slice() {
+                            [n] => pings.remove(n),
+                            _ => None,
+                        };
+                        if let Some(sent) = sent {
+                            last_pong = SystemTime::now();
+                            let rtt = last_pong.duration_since(sent).unwrap_or_default();
+                            let _ =
+                                sender.send(RecvMsg::Pong(config.relay.clone(), last_pong, rtt));
+                        }
+                    }
+                    // only returned when reading raw frames
+                    WsMessage::Frame(_) => {}
+                }
+            }
+            Some(Err(e)) => match e {
+                tungstenite::Error::Io(e) if is_would_block(&e) => {}
+                tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+                    closed = true;
+                }
+                // tungstenite can't read past a broken frame, start over
+                e => {
+                    config.log(log::Level::Error, format_args!("{:?}", e));
+                    closed = true;
+                }
+            },
+            None => {}
+        }
+
+        // flush once the socket has nothing more to read right now
+        if !batch.is_empty() && (batch.len() >= config.batch_size || !received_text) {
+            let _ = sender.send(RecvMsg::Batch(
+                config.relay.clone(),
+                std::mem::take(&mut batch),
+            ));
+        }
+
+        if SystemTime::now()
             .duration_since(last_ping)
             .expect("valid duration")
-            > Duration::from_secs(PING_INTERVAL)
+            > config.options.ping_interval
         {
             last_ping = SystemTime::now();
             ping_nonce = ping_nonce.wrapping_add(1);
-            _ = client.send_message(&OwnedMessage::Ping(vec![ping_nonce]));
+            match client.send(WsMessage::Ping(vec![ping_nonce])) {
+                Ok(_) => {
+                    pings.insert(ping_nonce, last_ping);
+                    Stats::incr(&config.stats.pings)
+                }
+                Err(tungstenite::Error::Io(e)) if is_would_block(&e) => {
+                    pings.insert(ping_nonce, last_ping);
+                    Stats::incr(&config.stats.pings)
+                }
+                Err(_) => {}
+            }
         }
 
-        if SystemTime::now()
-            .duration_since(last_pong)
-            .expect("valid duration")
-            > Duration::from_secs(3 * PING_INTERVAL)
+        if closed
+            || SystemTime::now()
+                .duration_since(last_pong)
+                .expect("valid duration")
+                > config.options.pong_timeout
         {
-            _ = sender.send(RecvMsg::Close);
-            return;
+            if !batch.is_empty() {
+                let _ = sender.send(RecvMsg::Batch(
+                    config.relay.clone(),
+                    std::mem::take(&mut batch),
+                ));
+            }
+            config.hooks.disconnected(&config.relay);
+            config.notify(ConnectionState::Disconnected);
+            match reconnect(&config) {
+                Some(c) => {
+                    client = c;
+                    let _ = sender.send(RecvMsg::Reconnected(config.relay.clone()));
+                    pings.clear();
+                    last_ping = SystemTime::now();
+                    last_pong = SystemTime::now();
+                    for m in subscriptions.values() {
+                        urgent.push_front(m.clone());
+                    }
+                    continue;
+                }
+                None => {
+                    _ = sender.send(RecvMsg::Close(config.relay.clone()));
+                    return;
+                }
+            }
         }
 
         if wait {
-            std::thread::sleep(Duration::from_micros(50));
+            std::thread::sleep(idle);
+            idle = (idle * 2).min(IDLE_MAX);
+        } else {
+            idle = IDLE_MIN;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let mut bucket = TokenBucket::new(100);
+        assert!(bucket.available());
+        // bigger than the bucket, let through then paid back
+        bucket.consume(250);
+        assert!(!bucket.available());
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!bucket.available());
+        std::thread::sleep(Duration::from_millis(1600));
+        assert!(bucket.available());
+        // refill is capped to the rate
+        std::thread::sleep(Duration::from_millis(1100));
+        assert!(bucket.available());
+        assert!(bucket.tokens <= 100.0);
+    }
+
+    #[test]
+    fn seen_events() {
+        let id = |n: u8| EventId::from_byte_array([n; 32]);
+        let mut seen = SeenEvents::new(2);
+        assert!(seen.insert(id(1)));
+        assert!(!seen.insert(id(1)));
+        assert!(seen.insert(id(2)));
+        // evicts the oldest one
+        assert!(seen.insert(id(3)));
+        assert!(seen.insert(id(1)));
+        assert!(!seen.insert(id(3)));
+        assert_eq!(seen.ids.len(), 2);
+        assert_eq!(seen.order.len(), 2);
+    }
+
+    #[test]
+    fn pinned_created_at() {
+        let pubkey = Keys::generate().public_key();
+        let note = EventBuilder::text_note("note", []);
+        assert!(!is_pinned(&note, pubkey));
+        let pinned = note.custom_created_at(Timestamp::from_secs(1_700_000_000));
+        assert!(is_pinned(&pinned, pubkey));
+    }
+
+    #[test]
+    fn filter_narrowed() {
+        assert!(is_filter_narrowed("Filter too broad"));
+        assert!(is_filter_narrowed("limit capped to 500"));
+        assert!(is_filter_narrowed("results truncated: filter limit"));
+        assert!(!is_filter_narrowed("rate-limited: slow down"));
+        assert!(!is_filter_narrowed("invalid: event too old"));
+        assert!(!is_filter_narrowed("narrow escape"));
+    }
+}
@@ -0,0 +1,189 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+/// Paces reconnection attempts.
+pub trait ReconnectStrategy: Send {
+    /// Delay to wait before reconnection `attempt` (starting at 1), `None`
+    /// to give up.
+    fn next_delay(&mut self, attempt: usize) -> Option<Duration>;
+}
+
+fn exhausted(attempt: usize, max_attempts: Option<usize>) -> bool {
+    max_attempts.is_some_and(|max| attempt > max)
+}
+
+/// Always wait the same delay.
+#[derive(Debug, Clone)]
+pub struct ConstantBackoff {
+    delay: Duration,
+    max_attempts: Option<usize>,
+}
+
+impl ConstantBackoff {
+    pub fn new(delay: Duration) -> Self {
+        ConstantBackoff {
+            delay,
+            max_attempts: None,
+        }
+    }
+
+    pub fn max_attempts(mut self, max: usize) -> Self {
+        self.max_attempts = Some(max);
+        self
+    }
+}
+
+impl ReconnectStrategy for ConstantBackoff {
+    fn next_delay(&mut self, attempt: usize) -> Option<Duration> {
+        (!exhausted(attempt, self.max_attempts)).then_some(self.delay)
+    }
+}
+
+/// Wait `step * attempt`, capped at `max`.
+#[derive(Debug, Clone)]
+pub struct LinearBackoff {
+    step: Duration,
+    max: Duration,
+    max_attempts: Option<usize>,
+}
+
+impl LinearBackoff {
+    pub fn new(step: Duration, max: Duration) -> Self {
+        LinearBackoff {
+            step,
+            max,
+            max_attempts: None,
+        }
+    }
+
+    pub fn max_attempts(mut self, max: usize) -> Self {
+        self.max_attempts = Some(max);
+        self
+    }
+}
+
+impl ReconnectStrategy for LinearBackoff {
+    fn next_delay(&mut self, attempt: usize) -> Option<Duration> {
+        if exhausted(attempt, self.max_attempts) {
+            return None;
+        }
+        let factor = u32::try_from(attempt).unwrap_or(u32::MAX);
+        Some(self.step.saturating_mul(factor).min(self.max))
+    }
+}
+
+/// Wait `min * 2^(attempt - 1)`, capped at `max`.
+#[derive(Debug, Clone)]
+pub struct ExponentialBackoff {
+    min: Duration,
+    max: Duration,
+    max_attempts: Option<usize>,
+    jitter: bool,
+}
+
+impl ExponentialBackoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        ExponentialBackoff {
+            min,
+            max,
+            max_attempts: None,
+            jitter: false,
+        }
+    }
+
+    pub fn max_attempts(mut self, max: usize) -> Self {
+        self.max_attempts = Some(max);
+        self
+    }
+
+    /// Wait a random delay between half the computed delay (at least `min`)
+    /// and the computed delay, so clients dropped at once don't all come
+    /// back at once.
+    pub fn jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+}
+
+impl ReconnectStrategy for ExponentialBackoff {
+    fn next_delay(&mut self, attempt: usize) -> Option<Duration> {
+        if exhausted(attempt, self.max_attempts) {
+            return None;
+        }
+        let shift = u32::try_from(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX)
+            .min(31);
+        let delay = self.min.saturating_mul(1 << shift).min(self.max);
+        if !self.jitter {
+            return Some(delay);
+        }
+        let low = (delay / 2).max(self.min).min(delay);
+        let spread = (delay - low).as_millis() as u64;
+        // no rng dependency, RandomState is seeded randomly
+        let random = RandomState::new().build_hasher().finish();
+        let offset = random.checked_rem(spread + 1).unwrap_or_default();
+        Some(low + Duration::from_millis(offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEC: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn constant_backoff() {
+        let mut backoff = ConstantBackoff::new(SEC).max_attempts(2);
+        assert_eq!(backoff.next_delay(1), Some(SEC));
+        assert_eq!(backoff.next_delay(2), Some(SEC));
+        assert_eq!(backoff.next_delay(3), None);
+        let mut unlimited = ConstantBackoff::new(SEC);
+        assert_eq!(unlimited.next_delay(usize::MAX), Some(SEC));
+    }
+
+    #[test]
+    fn linear_backoff() {
+        let mut backoff = LinearBackoff::new(SEC, 3 * SEC).max_attempts(5);
+        assert_eq!(backoff.next_delay(1), Some(SEC));
+        assert_eq!(backoff.next_delay(2), Some(2 * SEC));
+        assert_eq!(backoff.next_delay(5), Some(3 * SEC));
+        assert_eq!(backoff.next_delay(6), None);
+        let mut unlimited = LinearBackoff::new(SEC, 3 * SEC);
+        assert_eq!(unlimited.next_delay(usize::MAX), Some(3 * SEC));
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        let max = 60 * SEC;
+        let mut backoff = ExponentialBackoff::new(SEC, max).max_attempts(40);
+        assert_eq!(backoff.next_delay(1), Some(SEC));
+        assert_eq!(backoff.next_delay(2), Some(2 * SEC));
+        assert_eq!(backoff.next_delay(4), Some(8 * SEC));
+        assert_eq!(backoff.next_delay(7), Some(max));
+        // the shift saturates at 31 instead of overflowing
+        assert_eq!(backoff.next_delay(40), Some(max));
+        assert_eq!(backoff.next_delay(41), None);
+        let mut unbounded = ExponentialBackoff::new(SEC, Duration::MAX);
+        assert_eq!(unbounded.next_delay(usize::MAX), Some(SEC * (1 << 31)));
+    }
+
+    #[test]
+    fn exponential_backoff_jitter() {
+        let (min, max) = (SEC, 60 * SEC);
+        let mut backoff = ExponentialBackoff::new(min, max).jitter(true);
+        for attempt in 1..20 {
+            let delay = 2u32
+                .checked_pow(attempt as u32 - 1)
+                .map_or(max, |f| (min * f).min(max));
+            let low = (delay / 2).max(min);
+            for _ in 0..50 {
+                let jittered = backoff.next_delay(attempt).unwrap();
+                assert!(jittered >= low && jittered <= delay, "{jittered:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use nostr::event::{Event, EventId};
+
+/// Local storage for events received by a `WsClient`, see
+/// `WsClientBuilder::store()`.
+pub trait EventStore: Send + Sync {
+    fn put(&self, event: &Event);
+    fn get(&self, id: &EventId) -> Option<Event>;
+}
+
+/// Unbounded in-memory `EventStore`.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    events: Mutex<HashMap<EventId, Event>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.lock().expect("poisoned").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl EventStore for MemoryStore {
+    fn put(&self, event: &Event) {
+        self.events
+            .lock()
+            .expect("poisoned")
+            .insert(event.id, event.clone());
+    }
+
+    fn get(&self, id: &EventId) -> Option<Event> {
+        self.events.lock().expect("poisoned").get(id).cloned()
+    }
+}
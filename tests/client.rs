@@ -1,4 +1,7 @@
-use std::{sync::Once, time::Duration};
+use std::{
+    sync::{Arc, Mutex, Once},
+    time::Duration,
+};
 
 use env_logger::Env;
 use joinstr::nostr::{default_version, Pool, PoolPayload, PoolType};
@@ -6,9 +9,16 @@ use joinstr::{
     miniscript::bitcoin::{Amount, Network},
     nostr::{Fee, Timeline, Transport, Vpn},
 };
-use nostr::event::EventBuilder;
-use nostr::key::PublicKey;
-use utils::{clear_nostr_log, Relay};
+use nostr::event::{Event, EventBuilder, Kind, Tag};
+use nostr::key::{Keys, PublicKey};
+use nostr::message::{ClientMessage, RelayMessage, SubscriptionId};
+use nostr::nips::nip19::ToBech32;
+use nostr::types::{Filter, Timestamp};
+use simple_nostr_client::{
+    addressable_coordinate, normalize_relay_url, note_to_event_id, ConnectionState, Encryption,
+    Error, PublishMode, QueryOptions, RelayMarker, WsClient,
+};
+use utils::{clear_nostr_log, Forwarder, MockRelay, Relay};
 
 use crate::utils::dump_nostr_log;
 
@@ -32,12 +42,14 @@ pub fn test_dm() {
     let mut client_b = relay.new_client();
     clear_nostr_log(&mut relay);
 
-    client_a.subscribe_dm().unwrap();
+    client_a.subscribe_dm(None, None).unwrap();
 
     std::thread::sleep(Duration::from_secs(1));
     dump_nostr_log(&mut relay);
 
-    client_b.send_dm("test dm", &client_a.pubkey()).unwrap();
+    client_b
+        .send_dm("test dm", &client_a.pubkey().unwrap(), Encryption::Nip04)
+        .unwrap();
     std::thread::sleep(Duration::from_secs(3));
     dump_nostr_log(&mut relay);
 
@@ -59,6 +71,74 @@ pub fn test_dm() {
     }
 }
 
+#[test]
+fn test_dm_nip44() {
+    init_logger();
+
+    let relay = Relay::new();
+    let mut client_a = relay.new_client();
+    let mut client_b = relay.new_client();
+
+    client_a.subscribe_dm(None, None).unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+
+    client_b
+        .send_dm("test nip44", &client_a.pubkey().unwrap(), Encryption::Nip44)
+        .unwrap();
+
+    let event = client_a
+        .receive_timeout(Duration::from_secs(5))
+        .unwrap()
+        .unwrap();
+    assert_eq!(event.content, "test nip44");
+}
+
+#[test]
+fn test_private_message() {
+    init_logger();
+
+    let relay = Relay::new();
+    let mut client_a = relay.new_client();
+    let mut client_b = relay.new_client();
+
+    client_a.subscribe_private_messages(None).unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+
+    client_b
+        .send_private_message("test nip59", &client_a.pubkey().unwrap())
+        .unwrap();
+
+    let rumor = client_a
+        .receive_timeout(Duration::from_secs(5))
+        .unwrap()
+        .unwrap();
+    assert_eq!(rumor.kind, Kind::PrivateDirectMessage);
+    assert_eq!(rumor.content, "test nip59");
+    assert_eq!(rumor.pubkey, client_b.pubkey().unwrap());
+}
+
+#[test]
+fn test_receive_gift_wrap() {
+    init_logger();
+
+    let relay = Relay::new();
+    let mut client_a = relay.new_client();
+    let mut client_b = relay.new_client();
+
+    client_a.subscribe_private_messages(None).unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+
+    client_b
+        .send_private_message("unwrapped", &client_a.pubkey().unwrap())
+        .unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+
+    let rumor = client_a.try_receive().unwrap().unwrap();
+    assert_eq!(rumor.kind, Kind::PrivateDirectMessage);
+    assert_eq!(rumor.content, "unwrapped");
+    assert_eq!(rumor.pubkey, client_b.pubkey().unwrap());
+}
+
 #[test]
 fn test_pool_notif() {
     init_logger();
@@ -114,3 +194,713 @@ fn test_pool_notif() {
         assert!(counter < 10);
     }
 }
+
+#[test]
+fn test_addressable_coordinate() {
+    let keys = Keys::generate();
+
+    let note = EventBuilder::new(Kind::TextNote, "note", vec![])
+        .to_event(&keys)
+        .unwrap();
+    assert_eq!(addressable_coordinate(&note), None);
+
+    let list = EventBuilder::new(Kind::Custom(30000), "", vec![Tag::identifier("friends")])
+        .to_event(&keys)
+        .unwrap();
+    assert_eq!(
+        addressable_coordinate(&list).unwrap(),
+        format!("30000:{}:friends", keys.public_key())
+    );
+
+    let no_d_tag = EventBuilder::new(Kind::Custom(30000), "", vec![])
+        .to_event(&keys)
+        .unwrap();
+    assert_eq!(
+        addressable_coordinate(&no_d_tag).unwrap(),
+        format!("30000:{}:", keys.public_key())
+    );
+}
+
+#[test]
+fn test_bech32() {
+    let keys = Keys::generate();
+    let nsec = keys.secret_key().to_bech32().unwrap();
+    let builder = WsClient::new().keys_from_nsec(&nsec).unwrap();
+    assert_eq!(builder.get_keys().unwrap().public_key(), keys.public_key());
+
+    let npub = keys.public_key().to_bech32().unwrap();
+    assert!(matches!(
+        WsClient::new().keys_from_nsec(&npub),
+        Err(Error::InvalidBech32)
+    ));
+
+    let note = EventBuilder::new(Kind::TextNote, "note", vec![])
+        .to_event(&keys)
+        .unwrap();
+    assert_eq!(
+        note_to_event_id(&note.id.to_bech32().unwrap()).unwrap(),
+        note.id
+    );
+    assert!(note_to_event_id(&npub).is_err());
+}
+
+#[test]
+fn test_normalize_relay_url() {
+    assert_eq!(
+        normalize_relay_url("wss://Relay.Example.com:443/").unwrap(),
+        "wss://relay.example.com"
+    );
+    assert_eq!(
+        normalize_relay_url("ws://127.0.0.1:8080/nostr/").unwrap(),
+        "ws://127.0.0.1:8080/nostr"
+    );
+    assert!(matches!(
+        normalize_relay_url("https://relay.example.com"),
+        Err(Error::InvalidRelayUrl(_))
+    ));
+}
+
+#[test]
+fn test_query() {
+    init_logger();
+
+    let mut relay = Relay::new();
+    // read-only
+    let mut client_a = WsClient::new().relay(relay.url()).connect().unwrap();
+    let mut client_b = relay.new_client();
+    clear_nostr_log(&mut relay);
+
+    for i in 0..3 {
+        let note = EventBuilder::new(Kind::TextNote, format!("note {i}"), vec![]);
+        client_b.post_event(note).unwrap();
+    }
+    std::thread::sleep(Duration::from_secs(1));
+    dump_nostr_log(&mut relay);
+
+    let filter = Filter::new()
+        .author(client_b.pubkey().unwrap())
+        .kind(Kind::TextNote);
+    let events = client_a
+        .query(vec![filter.clone()], QueryOptions::default())
+        .unwrap();
+    assert_eq!(events.len(), 3);
+
+    let options = QueryOptions {
+        limit: Some(2),
+        ..Default::default()
+    };
+    let events = client_a.query(vec![filter], options).unwrap();
+    assert_eq!(events.len(), 2);
+
+    let note = EventBuilder::new(Kind::TextNote, "read-only", vec![]);
+    assert!(matches!(client_a.post_event(note), Err(Error::KeysMissing)));
+}
+
+#[test]
+fn test_count() {
+    init_logger();
+
+    // notes are counted, metadata refused, anything else is unknown
+    let relay = MockRelay::new(|msg| {
+        let ClientMessage::Count {
+            subscription_id,
+            filters,
+        } = msg
+        else {
+            return vec![];
+        };
+        let kind = filters[0].kinds.as_ref().and_then(|k| k.first().copied());
+        match kind {
+            Some(Kind::TextNote) => vec![RelayMessage::Count {
+                subscription_id,
+                count: 3,
+            }],
+            Some(Kind::Metadata) => vec![RelayMessage::Closed {
+                subscription_id,
+                message: "unsupported: no COUNT here".into(),
+            }],
+            _ => vec![RelayMessage::Notice {
+                message: "unknown message".into(),
+            }],
+        }
+    });
+    let mut client = WsClient::new().relay(relay.url()).connect().unwrap();
+    let timeout = Duration::from_secs(5);
+
+    let notes = Filter::new().kind(Kind::TextNote);
+    assert_eq!(client.count(vec![notes], timeout).unwrap(), 3);
+
+    let metadata = Filter::new().kind(Kind::Metadata);
+    assert!(matches!(
+        client.count(vec![metadata], timeout),
+        Err(Error::SubscriptionClosed { .. })
+    ));
+
+    let contacts = Filter::new().kind(Kind::ContactList);
+    match client.count(vec![contacts], timeout) {
+        Err(Error::CountUnsupported(notice)) => assert_eq!(notice, "unknown message"),
+        r => panic!("unexpected count: {r:?}"),
+    }
+    assert_eq!(client.try_notice().unwrap(), "unknown message");
+}
+
+#[test]
+fn test_export_import() {
+    init_logger();
+
+    let mut relay = Relay::new();
+    let mut client_a = relay.new_client();
+    let mut client_b = WsClient::new()
+        .relay(relay.url())
+        .keys(Keys::generate())
+        .replay_buffer(10)
+        .connect()
+        .unwrap();
+    clear_nostr_log(&mut relay);
+
+    for i in 0..2 {
+        let note = EventBuilder::new(Kind::TextNote, format!("note {i}"), vec![]);
+        client_a.post_event(note).unwrap();
+    }
+    std::thread::sleep(Duration::from_secs(1));
+    dump_nostr_log(&mut relay);
+
+    let filter = Filter::new()
+        .author(client_a.pubkey().unwrap())
+        .kind(Kind::TextNote);
+    client_b
+        .query(vec![filter.clone()], QueryOptions::default())
+        .unwrap();
+    let mut ndjson = Vec::new();
+    client_b.export_events(&mut ndjson).unwrap();
+
+    let other = Relay::new();
+    let mut client_c = other.new_client();
+    let sent = client_c.import_and_broadcast(&ndjson[..]).unwrap();
+    assert_eq!(sent.len(), 2);
+    std::thread::sleep(Duration::from_secs(1));
+
+    let events = client_c
+        .query(vec![filter], QueryOptions::default())
+        .unwrap();
+    assert_eq!(events.len(), 2);
+}
+
+#[test]
+fn test_multi_relay() {
+    init_logger();
+
+    let relay_a = Relay::new();
+    let relay_b = Relay::new();
+    let mut client = WsClient::new()
+        .relay(relay_a.url())
+        .relay(relay_b.url())
+        .keys(Keys::generate())
+        .connect()
+        .unwrap();
+    assert_eq!(client.get_relays(), vec![relay_a.url(), relay_b.url()]);
+
+    let note = EventBuilder::new(Kind::TextNote, "to both relays", vec![]);
+    client.post_event(note).unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+
+    let filter = Filter::new()
+        .author(client.pubkey().unwrap())
+        .kind(Kind::TextNote);
+    for relay in [relay_a, relay_b] {
+        let events = relay
+            .new_client()
+            .query(vec![filter.clone()], QueryOptions::default())
+            .unwrap();
+        assert_eq!(events.len(), 1);
+    }
+}
+
+#[test]
+fn test_publish_to_all() {
+    init_logger();
+
+    let relay_a = Relay::new();
+    let relay_b = Relay::new();
+    let mut client = WsClient::new()
+        .relay(relay_a.url())
+        .relay(relay_b.url())
+        .keys(Keys::generate())
+        .connect()
+        .unwrap();
+
+    let note = EventBuilder::new(Kind::TextNote, "to both relays", vec![]);
+    let report = client.publish_to_all(note, Duration::from_secs(5)).unwrap();
+    assert_eq!(report.accepted.len(), 2);
+    assert!(report.rejected.is_empty());
+    assert!(report.timed_out.is_empty());
+}
+
+#[test]
+fn test_publish_round_robin() {
+    init_logger();
+
+    let relay_a = Relay::new();
+    let relay_b = Relay::new();
+    let keys = Keys::generate();
+    let mut client = WsClient::new()
+        .relay(relay_a.url())
+        .relay(relay_b.url())
+        .keys(keys.clone())
+        .publish_mode(PublishMode::RoundRobin)
+        .connect()
+        .unwrap();
+
+    for i in 0..4 {
+        client.publish_note(format!("note {i}")).unwrap();
+    }
+    std::thread::sleep(Duration::from_millis(500));
+
+    let filter = Filter::new().author(keys.public_key()).kind(Kind::TextNote);
+    for relay in [relay_a, relay_b] {
+        let mut reader = relay.new_client();
+        let events = reader
+            .query(vec![filter.clone()], QueryOptions::default())
+            .unwrap();
+        assert_eq!(events.len(), 2);
+    }
+}
+
+#[test]
+fn test_preview_event() {
+    init_logger();
+
+    let relay = Relay::new();
+    let mut client_a = relay.new_client();
+    let mut client_b = relay.new_client();
+
+    let note = EventBuilder::new(Kind::TextNote, "previewed", vec![]);
+    let preview = client_b.preview_event(note).unwrap();
+    assert_eq!(
+        client_b.post_signed_event(preview.clone()).unwrap(),
+        preview.id
+    );
+    std::thread::sleep(Duration::from_secs(1));
+
+    let filter = Filter::new().id(preview.id);
+    let events = client_a
+        .query(vec![filter], QueryOptions::default())
+        .unwrap();
+    assert_eq!(events, vec![preview]);
+}
+
+#[test]
+fn test_reply_tags() {
+    init_logger();
+
+    let relay = Relay::new();
+    let mut alice = relay.new_client();
+    let mut bob = relay.new_client();
+    let (alice_pk, bob_pk) = (alice.pubkey().unwrap(), bob.pubkey().unwrap());
+    let tags = |event: &Event, kind: &str| -> Vec<Vec<String>> {
+        event
+            .tags
+            .iter()
+            .map(|t| t.as_slice().to_vec())
+            .filter(|t| t[0] == kind)
+            .collect()
+    };
+
+    let root = alice
+        .preview_event(EventBuilder::text_note("root", []))
+        .unwrap();
+    alice.post_signed_event(root.clone()).unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+
+    // replying to the root marks it as such
+    bob.reply("first".into(), &root).unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+    let filter = Filter::new().author(bob_pk).event(root.id);
+    let first = alice
+        .query(vec![filter], QueryOptions::default())
+        .unwrap()
+        .pop()
+        .unwrap();
+    assert_eq!(
+        tags(&first, "e"),
+        vec![vec![
+            "e".to_string(),
+            root.id.to_hex(),
+            "".into(),
+            "root".into()
+        ]]
+    );
+    assert_eq!(
+        tags(&first, "p"),
+        vec![vec!["p".to_string(), alice_pk.to_hex()]]
+    );
+
+    // deeper in the thread the root is kept and the parent is the reply
+    alice.reply("second".into(), &first).unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+    let filter = Filter::new().author(alice_pk).event(first.id);
+    let second = bob
+        .query(vec![filter], QueryOptions::default())
+        .unwrap()
+        .pop()
+        .unwrap();
+    assert_eq!(
+        tags(&second, "e"),
+        vec![
+            vec!["e".to_string(), root.id.to_hex(), "".into(), "root".into()],
+            vec![
+                "e".to_string(),
+                first.id.to_hex(),
+                "".into(),
+                "reply".into()
+            ],
+        ]
+    );
+    assert_eq!(
+        tags(&second, "p"),
+        vec![
+            vec!["p".to_string(), bob_pk.to_hex()],
+            vec!["p".to_string(), alice_pk.to_hex()],
+        ]
+    );
+}
+
+#[test]
+fn test_publish_batch() {
+    init_logger();
+
+    let relay = Relay::new();
+    let mut client = relay.new_client();
+
+    let notes = (0..5)
+        .map(|i| EventBuilder::new(Kind::TextNote, format!("note {i}"), vec![]))
+        .collect();
+    let results = client
+        .publish_batch_and_confirm(notes, Duration::from_secs(5))
+        .unwrap();
+    assert_eq!(results.len(), 5);
+    assert!(results.iter().all(|(_, r)| r.is_ok()));
+
+    let filter = Filter::new()
+        .author(client.pubkey().unwrap())
+        .kind(Kind::TextNote);
+    let events = client.query(vec![filter], QueryOptions::default()).unwrap();
+    assert_eq!(events.len(), 5);
+}
+
+#[test]
+fn test_relay_list() {
+    init_logger();
+
+    let relay = Relay::new();
+    let mut client = relay.new_client();
+
+    let relays = vec![
+        (
+            "wss://read.example.com".to_string(),
+            Some(RelayMarker::Read),
+        ),
+        (
+            "wss://write.example.com".to_string(),
+            Some(RelayMarker::Write),
+        ),
+        ("wss://both.example.com".to_string(), None),
+    ];
+    client.publish_relay_list(relays.clone()).unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+
+    let pubkey = client.pubkey().unwrap();
+    let fetched = client
+        .fetch_relay_list(pubkey, Duration::from_secs(5))
+        .unwrap();
+    assert_eq!(fetched, relays);
+}
+
+#[test]
+fn test_contacts() {
+    init_logger();
+
+    let relay = Relay::new();
+    let mut client = relay.new_client();
+
+    let keys: Vec<_> = (0..4).map(|_| Keys::generate().public_key()).collect();
+    let relay_url = "wss://relay.example.com".to_string();
+    client
+        .set_contacts(vec![
+            (keys[0], None, None),
+            (keys[1], Some(relay_url.clone()), None),
+            (keys[2], None, Some("carol".into())),
+            (keys[3], Some(relay_url.clone()), Some("dave".into())),
+        ])
+        .unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+
+    let filter = Filter::new()
+        .author(client.pubkey().unwrap())
+        .kind(Kind::ContactList);
+    let event = client
+        .query(vec![filter], QueryOptions::default())
+        .unwrap()
+        .pop()
+        .unwrap();
+    let tags: Vec<Vec<String>> = event.tags.iter().map(|t| t.as_slice().to_vec()).collect();
+    let p = |values: &[&str]| -> Vec<String> {
+        std::iter::once("p")
+            .chain(values.iter().copied())
+            .map(String::from)
+            .collect()
+    };
+    assert_eq!(
+        tags,
+        vec![
+            p(&[&keys[0].to_hex()]),
+            p(&[&keys[1].to_hex(), &relay_url]),
+            p(&[&keys[2].to_hex(), "", "carol"]),
+            p(&[&keys[3].to_hex(), &relay_url, "dave"]),
+        ]
+    );
+}
+
+#[test]
+fn test_auth() {
+    init_logger();
+
+    // ask for auth on REQ, then reject the auth event
+    let relay = MockRelay::new(|msg| match msg {
+        ClientMessage::Req { .. } => vec![RelayMessage::Auth {
+            challenge: "challenge-123".into(),
+        }],
+        ClientMessage::Auth(event) => vec![RelayMessage::Ok {
+            event_id: event.id,
+            status: false,
+            message: "restricted: not allowed".into(),
+        }],
+        _ => vec![],
+    });
+    let sent = Arc::new(Mutex::new(None));
+    let hook_sent = sent.clone();
+    let mut client = WsClient::new()
+        .relay(relay.url())
+        .keys(Keys::generate())
+        .auto_auth(true)
+        .on_auth(Box::new(move |event| {
+            *hook_sent.lock().unwrap() = Some(event.clone());
+        }))
+        .connect()
+        .unwrap();
+
+    client
+        .subscribe(vec![Filter::new().kind(Kind::TextNote)])
+        .unwrap();
+    let mut rejected = None;
+    for _ in 0..50 {
+        match client.try_receive() {
+            Err(Error::Auth(reason)) => {
+                rejected = Some(reason);
+                break;
+            }
+            r => assert!(r.unwrap().is_none()),
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(rejected.unwrap(), "restricted: not allowed");
+
+    // the challenge is echoed verbatim
+    let event = sent.lock().unwrap().take().unwrap();
+    assert_eq!(event.kind, Kind::Authentication);
+    let tags: Vec<Vec<String>> = event.tags.iter().map(|t| t.as_slice().to_vec()).collect();
+    assert!(tags.contains(&vec!["challenge".to_string(), "challenge-123".to_string()]));
+    assert!(tags
+        .iter()
+        .any(|t| t[0] == "relay" && t[1].starts_with(&relay.url())));
+    assert_eq!(client.last_auth_event(), Some(event));
+}
+
+#[test]
+fn test_strict_subscriptions() {
+    init_logger();
+
+    // send an event under the REQ id and one under an id never opened
+    let relay = MockRelay::new(|msg| {
+        let ClientMessage::Req {
+            subscription_id, ..
+        } = msg
+        else {
+            return vec![];
+        };
+        let keys = Keys::generate();
+        let event = |content: &str| {
+            Box::new(
+                EventBuilder::text_note(content, [])
+                    .to_event(&keys)
+                    .unwrap(),
+            )
+        };
+        vec![
+            RelayMessage::Event {
+                subscription_id: SubscriptionId::new("forged"),
+                event: event("forged"),
+            },
+            RelayMessage::Event {
+                subscription_id,
+                event: event("requested"),
+            },
+        ]
+    });
+    let mut client = WsClient::new()
+        .relay(relay.url())
+        .strict_subscriptions(true)
+        .connect()
+        .unwrap();
+
+    client
+        .subscribe(vec![Filter::new().kind(Kind::TextNote)])
+        .unwrap();
+    let event = client
+        .receive_timeout(Duration::from_secs(5))
+        .unwrap()
+        .unwrap();
+    assert_eq!(event.content, "requested");
+    assert_eq!(client.dropped_events(), 1);
+    assert_eq!(client.stats().dropped_events, 1);
+}
+
+#[test]
+fn test_reconnect_replay() {
+    init_logger();
+
+    let relay = Relay::new();
+    let forwarder = Forwarder::new(&relay);
+    let mut poster = relay.new_client();
+    // an hour old, it would look like a skewed clock if taken for a live event
+    let created_at = Timestamp::from(Timestamp::now().as_u64() - 3600);
+    let note = EventBuilder::new(Kind::TextNote, "stored", vec![]).custom_created_at(created_at);
+    poster.post_event(note).unwrap();
+    std::thread::sleep(Duration::from_millis(500));
+
+    let mut client = WsClient::new()
+        .relay(forwarder.url())
+        .keys(Keys::generate())
+        .reconnect_backoff(Duration::from_millis(100), Duration::from_millis(200))
+        .connect()
+        .unwrap();
+    let states = client.connection_events();
+    let filter = Filter::new().author(poster.pubkey().unwrap());
+    let id = client.subscribe(vec![filter]).unwrap();
+    client.wait_for_eose(&id, Duration::from_secs(5)).unwrap();
+    assert!(client.try_receive().unwrap().is_some());
+
+    forwarder.cut();
+    let mut disconnected = false;
+    loop {
+        let (_, state) = states.recv_timeout(Duration::from_secs(5)).unwrap();
+        disconnected |= state == ConnectionState::Disconnected;
+        if disconnected && state == ConnectionState::Connected {
+            break;
+        }
+    }
+
+    // sent again on the replayed REQ
+    let event = client
+        .receive_timeout(Duration::from_secs(5))
+        .unwrap()
+        .unwrap();
+    assert_eq!(event.content, "stored");
+    client.wait_for_eose(&id, Duration::from_secs(5)).unwrap();
+    assert!(client.estimated_clock_skew().is_none());
+    assert!(client.subscription_latency(&id).is_some());
+}
+
+#[test]
+fn test_try_receive_for() {
+    init_logger();
+
+    let relay = Relay::new();
+    let mut client_a = relay.new_client();
+    let mut client_b = relay.new_client();
+    let mut client_c = relay.new_client();
+
+    let sub_b = client_a
+        .subscribe(vec![Filter::new().author(client_b.pubkey().unwrap())])
+        .unwrap();
+    let sub_c = client_a
+        .subscribe(vec![Filter::new().author(client_c.pubkey().unwrap())])
+        .unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+
+    client_b.publish_note("from b").unwrap();
+    client_c.publish_note("from c").unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+
+    let event = client_a.try_receive_for(&sub_c).unwrap().unwrap();
+    assert_eq!(event.content, "from c");
+    assert!(client_a.try_receive_for(&sub_c).unwrap().is_none());
+    let event = client_a.try_receive_for(&sub_b).unwrap().unwrap();
+    assert_eq!(event.content, "from b");
+}
+
+#[test]
+fn test_add_remove_relay() {
+    init_logger();
+
+    let relay_a = Relay::new();
+    let relay_b = Relay::new();
+    let mut client = relay_a.new_client();
+
+    let states = client.connection_events();
+    client.add_relay(relay_b.url()).unwrap();
+    assert_eq!(client.get_relays(), vec![relay_a.url(), relay_b.url()]);
+    loop {
+        let (relay, state) = states.recv_timeout(Duration::from_secs(5)).unwrap();
+        if relay == relay_b.url() {
+            assert_eq!(state, ConnectionState::Connected);
+            break;
+        }
+    }
+
+    client.remove_relay(&relay_a.url()).unwrap();
+    assert_eq!(client.get_relays(), vec![relay_b.url()]);
+    assert!(matches!(
+        client.remove_relay(&relay_a.url()),
+        Err(Error::RelayNotFound(_))
+    ));
+
+    let note = EventBuilder::new(Kind::TextNote, "to relay b", vec![]);
+    client.post_event(note).unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+
+    let filter = Filter::new()
+        .author(client.pubkey().unwrap())
+        .kind(Kind::TextNote);
+    let events = relay_b
+        .new_client()
+        .query(vec![filter], QueryOptions::default())
+        .unwrap();
+    assert_eq!(events.len(), 1);
+}
+
+#[test]
+fn test_events_iterator() {
+    init_logger();
+
+    let relay = Relay::new();
+    let mut client_a = relay.new_client();
+    let mut client_b = relay.new_client();
+
+    let filter = Filter::new()
+        .author(client_b.pubkey().unwrap())
+        .kind(Kind::TextNote);
+    client_a.subscribe(vec![filter]).unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+
+    for i in 0..3 {
+        client_b.publish_note(format!("note {i}")).unwrap();
+    }
+
+    let notes: Vec<_> = client_a
+        .events()
+        .take(3)
+        .map(|event| event.unwrap().content)
+        .collect();
+    assert_eq!(notes, vec!["note 0", "note 1", "note 2"]);
+}
@@ -1,6 +1,16 @@
+use std::{
+    io,
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+
 use nostr::key::Keys;
+use nostr::message::{ClientMessage, RelayMessage};
+use nostr::util::JsonUtil;
 use nostrd::NostrD;
 use simple_nostr_client::WsClient;
+use tungstenite::Message;
 
 pub struct Relay {
     nostrd: NostrD,
@@ -43,3 +53,101 @@ pub fn dump_nostr_log(relay: &mut Relay) {
 pub fn clear_nostr_log(relay: &mut Relay) {
     while relay.nostrd.logs.try_recv().is_ok() {}
 }
+
+/// Forwards a local port to a relay, `cut()` drops the open connections as a
+/// network failure would.
+#[allow(dead_code)]
+pub struct Forwarder {
+    port: u16,
+    streams: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+#[allow(dead_code)]
+impl Forwarder {
+    pub fn new(relay: &Relay) -> Self {
+        let url = relay.url();
+        let target = url
+            .trim_start_matches("ws://")
+            .trim_end_matches('/')
+            .to_string();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let streams = Arc::new(Mutex::new(Vec::new()));
+        let open = streams.clone();
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                let (Ok(client), Ok(upstream)) = (client, TcpStream::connect(&target)) else {
+                    continue;
+                };
+                open.lock()
+                    .unwrap()
+                    .extend([client.try_clone().unwrap(), upstream.try_clone().unwrap()]);
+                pipe(client.try_clone().unwrap(), upstream.try_clone().unwrap());
+                pipe(upstream, client);
+            }
+        });
+        Forwarder { port, streams }
+    }
+
+    pub fn url(&self) -> String {
+        format!("ws://127.0.0.1:{}", self.port)
+    }
+
+    pub fn cut(&self) {
+        for stream in self.streams.lock().unwrap().drain(..) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// A relay answering each client message with the messages `answer` returns,
+/// for the replies nostrd doesn't give.
+#[allow(dead_code)]
+pub struct MockRelay {
+    port: u16,
+}
+
+#[allow(dead_code)]
+impl MockRelay {
+    pub fn new<F>(answer: F) -> Self
+    where
+        F: Fn(ClientMessage) -> Vec<RelayMessage> + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let answer = Arc::new(answer);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let Ok(mut socket) = tungstenite::accept(stream) else {
+                    continue;
+                };
+                let answer = answer.clone();
+                thread::spawn(move || {
+                    while let Ok(msg) = socket.read() {
+                        let Message::Text(text) = msg else {
+                            continue;
+                        };
+                        let Ok(msg) = ClientMessage::from_json(text) else {
+                            continue;
+                        };
+                        for reply in answer(msg) {
+                            let _ = socket.send(Message::Text(reply.as_json()));
+                        }
+                    }
+                });
+            }
+        });
+        MockRelay { port }
+    }
+
+    pub fn url(&self) -> String {
+        format!("ws://127.0.0.1:{}", self.port)
+    }
+}
+
+fn pipe(mut from: TcpStream, mut to: TcpStream) {
+    thread::spawn(move || {
+        let _ = io::copy(&mut from, &mut to);
+        let _ = to.shutdown(Shutdown::Both);
+    });
+}